@@ -0,0 +1,59 @@
+//! Pick the linker script for the boot path we are building, and with the
+//! `ksyms` feature generate the kernel symbol table for `backtrace`.
+use std::env;
+use std::fs;
+use std::path::Path;
+
+fn main() {
+    let dir = env::var("CARGO_MANIFEST_DIR").unwrap();
+    let script = if env::var_os("CARGO_FEATURE_SBI").is_some() {
+        "kernel-sbi.ld"
+    } else {
+        "kernel.ld"
+    };
+    // -L so the scripts can INCLUDE kernel-common.ld.
+    println!("cargo:rustc-link-arg=-L{}", dir);
+    println!("cargo:rustc-link-arg=-T{}/{}", dir, script);
+    println!("cargo:rerun-if-changed=build.rs");
+    println!("cargo:rerun-if-changed=kernel.ld");
+    println!("cargo:rerun-if-changed=kernel-sbi.ld");
+    println!("cargo:rerun-if-changed=kernel-common.ld");
+
+    if env::var_os("CARGO_FEATURE_KSYMS").is_some() {
+        ksyms();
+    }
+}
+
+/// The kernel can't know its own symbols until it is linked, so the table
+/// comes from `nm` output of a previous build, named by $KSYMS (see
+/// `make ksyms`). Only the table's contents change between the two builds,
+/// and it lives in .rodata after .text, so function addresses stay put.
+/// Without $KSYMS the table is empty and backtraces print bare addresses.
+fn ksyms() {
+    println!("cargo:rerun-if-env-changed=KSYMS");
+    let mut syms: Vec<(u64, String)> = Vec::new();
+    if let Some(path) = env::var_os("KSYMS") {
+        println!("cargo:rerun-if-changed={}", path.to_string_lossy());
+        let nm = fs::read_to_string(&path).expect("Can't read $KSYMS");
+        // "<addr> <type> <name>", we want text symbols.
+        for line in nm.lines() {
+            let fields: Vec<&str> = line.splitn(3, ' ').collect();
+            if fields.len() != 3 || !matches!(fields[1], "t" | "T" | "w" | "W") {
+                continue;
+            }
+            if let Ok(addr) = u64::from_str_radix(fields[0], 16) {
+                syms.push((addr, fields[2].to_string()));
+            }
+        }
+    }
+    syms.sort();
+    syms.dedup_by_key(|(addr, _)| *addr);
+
+    let mut out = String::from("pub static KSYMS: &[(usize, &str)] = &[\n");
+    for (addr, name) in syms {
+        out += &format!("    ({:#x}, {:?}),\n", addr, name);
+    }
+    out += "];\n";
+    let dest = Path::new(&env::var("OUT_DIR").unwrap()).join("ksyms.rs");
+    fs::write(dest, out).unwrap();
+}
@@ -2,12 +2,16 @@
 
 ##
 ## This is just a wrapper for QEMU that adds the -s -S (for gdb) flags when
-## DEBUG is set, to be used as a binary runner by Cargo.
+## DEBUG is set, to be used as a binary runner by Cargo. Set MEM to change the
+## amount of RAM (e.g. MEM=512M) and SMP to change the number of harts (e.g.
+## SMP=4), the kernel finds both in the device tree. Set BIOS=default to boot
+## under OpenSBI (needs a kernel built with `--features sbi`). APPEND is passed
+## on as the kernel command line (e.g. APPEND="log=info log.vm=debug").
 ##
 
 set -euo pipefail
 
-FLAGS=(-machine virt -smp 2 -m 128M -bios none -nographic)
+FLAGS=(-machine virt -smp "${SMP:-2}" -m "${MEM:-128M}" -bios "${BIOS:-none}" -nographic)
 
 print_help() { echo "$(tput setaf 2)$(tput bold)(info)$(tput sgr0) $1"; }
 
@@ -16,5 +20,8 @@ if [ -n "${DEBUG+x}" ] ; then
     FLAGS+=(-s -S)
     print_help "Starting QEMU in debug mode (connect with gdb)"
 fi
+if [ -n "${APPEND:-}" ] ; then
+    FLAGS+=(-append "$APPEND")
+fi
 set -x
 exec "qemu-system-$1" "${FLAGS[@]}" -kernel "$2"
@@ -1,4 +1,9 @@
 use core::arch::global_asm;
 
-global_asm!(include_str!("asm/entry.s"));
+// SBI_BOOT selects the S-mode (OpenSBI) boot path in entry.s.
+#[cfg(not(feature = "sbi"))]
+global_asm!(".set SBI_BOOT, 0", include_str!("asm/entry.s"));
+#[cfg(feature = "sbi")]
+global_asm!(".set SBI_BOOT, 1", include_str!("asm/entry.s"));
 global_asm!(include_str!("asm/trap.s"));
+global_asm!(include_str!("asm/switch.s"));
@@ -5,9 +5,16 @@
 # devices (CLINT, PLIC, UART NS16550A, ...).  This entry function is loaded at
 # address 0x80000000 since it is a .text section and the linker lays those out
 # first. This entry function's job is to set up the kernel stack so we have some
-# space to work. Refer to src/param.rs for general memory layout. The kernel
-# stack depends on the number of harts on the h/w (or qemu).  We mostly
+# space to work. Refer to src/param.rs for general memory layout. The linker
+# script reserves stacks for up to MAX_HARTS harts, hart i's stacks sit i slots
+# below _stacks_end / _intstacks_end.  We mostly
 # reference this from `xv6-riscv/kernel/entry.S`.
+#
+# The previous boot stage hands us a0 = hartid and a1 = device tree blob
+# address. Only temporaries are used below so both reach _start untouched.
+# We go by a0 rather than mhartid since under OpenSBI (SBI_BOOT, set by
+# src/asm.rs for the `sbi` feature) we start in S-mode and can't read
+# M-mode CSRs. For the same reason there is no mscratch to set up then.
 
     .option norvc
     .section .text.entry
@@ -21,27 +28,36 @@ _entry:
     .option pop
     # Set up stack per of hart ids according to linker script
 
+    # Park harts we have no stacks for (see MAX_HARTS in kernel-common.ld).
+    mv t1, a0
+    .extern _max_harts
+    lui t0, %hi(_max_harts)
+    addi t0, t0, %lo(_max_harts)
+    bgeu t1, t0, spin
+
     # Add 4k guard page per hart
-    csrr a1, mhartid
-    #sll a1, a1, 1 # Multiple hartid by 2 to get alternating pages
-    li a0, 0x3000
-    mul a1, a1, a0
+    mv t1, a0
+    #sll t1, t1, 1 # Multiple hartid by 2 to get alternating pages
+    li t0, 0x3000
+    mul t1, t1, t0
     .extern _stacks_end
-    la a2, _stacks_end
-    sub sp, a2, a1
+    la t2, _stacks_end
+    sub sp, t2, t1
 
     .extern _intstacks_end
-    csrr a1, mhartid
-    li a0, 0x4000
-    mul a1, a1, a0
-    la a2, _intstacks_end
-    sub a2, a2, a1
-    csrw mscratch, a2 # Write per hart mscratch pad
-    li a0, 0x2000
-    sub a2, a2, a0 # Move sp down by scratch pad page + guard page
-    csrw sscratch, a2 # Write per hart sscratch pad
+    mv t1, a0
+    li t0, 0x4000
+    mul t1, t1, t0
+    la t2, _intstacks_end
+    sub t2, t2, t1
+.if SBI_BOOT == 0
+    csrw mscratch, t2 # Write per hart mscratch pad
+.endif
+    li t0, 0x2000
+    sub t2, t2, t0 # Move sp down by scratch pad page + guard page
+    csrw sscratch, t2 # Write per hart sscratch pad
 
-    # Jump to _start in src/main.rs
+    # Jump to _start(hartid, dtb) in src/main.rs
     .extern _start
     call _start
 spin:
@@ -32,6 +32,7 @@
     sd x28, 224(sp)
     sd x29, 232(sp)
     sd x30, 240(sp)
+    sd x31, 248(sp)
 .endm
 
 .macro load_gp_regs
@@ -65,6 +66,7 @@
     ld x28, 224(sp)
     ld x29, 232(sp)
     ld x30, 240(sp)
+    ld x31, 248(sp)
 
     addi sp, sp, 256
 .endm
@@ -96,6 +98,7 @@ __mtrapvec:
     save_gp_regs
 
     .extern s_handler
+    mv a0, sp # Saved registers, see `trap::TrapRegs`.
     call s_handler
 
     load_gp_regs
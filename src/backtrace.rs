@@ -0,0 +1,105 @@
+//! Stack backtraces by walking the frame pointer chain.
+//!
+//! The kernel is built with `-C force-frame-pointers=yes` (see
+//! `.cargo/config.toml`), so every frame starts with the standard RISC-V
+//! frame record: the return address at `fp - 8` and the caller's `fp` at
+//! `fp - 16`. We only follow pointers that land inside the kernel stacks,
+//! so a corrupt chain ends the trace rather than faulting. The trap vectors
+//! leave `s0` alone, so a trace taken in a trap handler carries on into the
+//! interrupted code (minus the trapping function itself, that is in `sepc`).
+//!
+//! With the `ksyms` feature and a symbol list from a previous build (see
+//! `build.rs` and `make ksyms`) addresses are printed as `function+offset`.
+use crate::hw;
+use crate::hw::param::*;
+use crate::hw::riscv::read_fp;
+
+/// Most frames `print` will show.
+pub const MAX_FRAMES: usize = 32;
+
+#[cfg(feature = "ksyms")]
+mod ksyms {
+    // Generated by build.rs: `static KSYMS: &[(usize, &str)]`, sorted by address.
+    include!(concat!(env!("OUT_DIR"), "/ksyms.rs"));
+}
+
+/// Whether `addr` is on one of the kernel stacks, or the running kernel
+/// thread's.
+pub(crate) fn in_stacks(addr: usize) -> bool {
+    let kstack = hw::this_hart().peek_process(|p| p.kstack()).flatten();
+    (addr > stacks_start().addr() && addr <= stacks_end().addr())
+        || (addr > intstacks_start().addr() && addr <= intstacks_end().addr())
+        || kstack.map_or(false, |stack| addr > stack.start && addr <= stack.end)
+}
+
+/// Fill `trace` with return addresses starting from frame `fp`.
+/// Returns how many were found.
+pub fn walk(mut fp: usize, trace: &mut [usize]) -> usize {
+    let mut depth = 0;
+    while depth < trace.len() {
+        if fp & 0x7 != 0 || !in_stacks(fp) {
+            break;
+        }
+        // RISC-V frame layout: fp[-1] = ra, fp[-2] = caller's fp.
+        unsafe {
+            trace[depth] = (fp as *const usize).sub(1).read();
+            fp = (fp as *const usize).sub(2).read();
+        }
+        if trace[depth] == 0 {
+            break;
+        }
+        depth += 1;
+    }
+    depth
+}
+
+/// Return addresses of the caller's callers, innermost first.
+#[inline(always)]
+pub fn capture(trace: &mut [usize]) -> usize {
+    walk(read_fp(), trace)
+}
+
+/// Function containing `addr` and the offset into it, if we have symbols.
+#[cfg(feature = "ksyms")]
+pub fn symbolize(addr: usize) -> Option<(&'static str, usize)> {
+    let syms = core::hint::black_box(ksyms::KSYMS);
+    let idx = match syms.binary_search_by_key(&addr, |&(start, _)| start) {
+        Ok(idx) => idx,
+        Err(0) => return None,
+        Err(idx) => idx - 1,
+    };
+    let (start, name) = syms[idx];
+    Some((name, addr - start))
+}
+
+#[cfg(not(feature = "ksyms"))]
+pub fn symbolize(_addr: usize) -> Option<(&'static str, usize)> {
+    None
+}
+
+/// Print one address, resolved if possible.
+pub fn print_addr(depth: usize, addr: usize) {
+    match symbolize(addr) {
+        Some((name, offset)) => println!("  #{:<2} {:#x} {}+{:#x}", depth, addr, name, offset),
+        None => println!("  #{:<2} {:#x}", depth, addr),
+    }
+}
+
+/// Print the chain of return addresses from frame `fp` up.
+pub fn print_from(fp: usize) {
+    let mut trace = [0; MAX_FRAMES];
+    let depth = walk(fp, &mut trace);
+    println!("Backtrace:");
+    for (i, addr) in trace[..depth].iter().enumerate() {
+        print_addr(i, *addr);
+    }
+    if depth == 0 {
+        println!("  (no frames, built without frame pointers?)");
+    }
+}
+
+/// Print the caller's backtrace.
+#[inline(always)]
+pub fn print() {
+    print_from(read_fp());
+}
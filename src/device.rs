@@ -1,3 +1,186 @@
 //! Memory Mapped I/O Devices.
+//!
+//! Devices are discovered from the boot device tree. Each driver lists the
+//! `compatible` strings it handles, and `probe()` binds it to every matching
+//! node, recording the result in a table the rest of the kernel can query
+//! (the kernel page table maps every bound device, for example). Without a
+//! device tree we fall back to the QEMU virt addresses in `hw::param`.
 pub mod clint;
+pub mod finisher;
+pub mod plic;
+pub mod rtc;
+pub mod sswi;
 pub mod uart;
+pub mod virtio;
+
+use crate::hw::fdt::{self, Node};
+use crate::hw::param::*;
+use crate::lock::rwlock::RwLock;
+
+/// Most devices we keep track of.
+pub const MAX_DEVICES: usize = 32;
+
+/// A device bound to a driver.
+#[derive(Copy, Clone, Debug)]
+pub struct Device {
+    pub driver: &'static str,
+    pub name: &'static str,
+    pub base: usize,
+    pub size: usize,
+    pub irq: Option<u32>,
+}
+
+#[derive(Debug)]
+pub enum DeviceError {
+    NoReg,
+    NotPresent, // Probe found nothing behind the address (empty virtio slot, ...).
+    Unsupported,
+}
+
+/// Binds to device tree nodes with a matching `compatible` string.
+pub struct Driver {
+    pub name: &'static str,
+    pub compatible: &'static [&'static str],
+    /// Check and set up the device. Runs on hart 0 with paging off.
+    pub probe: fn(&Device) -> Result<(), DeviceError>,
+}
+
+static DRIVERS: [&Driver; 7] = [
+    &uart::DRIVER,
+    &clint::DRIVER,
+    &sswi::DRIVER,
+    &plic::DRIVER,
+    &virtio::DRIVER,
+    &rtc::DRIVER,
+    &finisher::DRIVER,
+];
+
+/// Devices we assume are there when booted without a device tree.
+/// (driver, base, size, irq) for the QEMU virt machine.
+static FALLBACK: [(&str, usize, usize, Option<u32>); 5] = [
+    ("uart", UART_BASE, 0x100, Some(10)),
+    ("clint", CLINT_BASE, 0x10000, None),
+    ("plic", PLIC_BASE, 0x600000, None),
+    ("rtc", RTC_BASE, 0x1000, Some(11)),
+    ("finisher", TEST_BASE, 0x1000, None),
+];
+
+/// Bound devices, filled in by `probe()`.
+#[derive(Copy, Clone)]
+pub struct Devices {
+    devices: [Option<Device>; MAX_DEVICES],
+    len: usize,
+}
+
+/// Written only while probing, read by every driver lookup after.
+static DEVICES: RwLock<Devices> = RwLock::new(Devices {
+    devices: [None; MAX_DEVICES],
+    len: 0,
+});
+
+impl Devices {
+    pub fn iter(&self) -> impl Iterator<Item = &Device> {
+        self.devices[..self.len].iter().flatten()
+    }
+
+    fn push(&mut self, device: Device) {
+        if self.len == MAX_DEVICES {
+            log!(Warning, "Device table full, dropping {}", device.name);
+            return;
+        }
+        self.devices[self.len] = Some(device);
+        self.len += 1;
+    }
+}
+
+/// Snapshot of every bound device.
+pub fn devices() -> Devices {
+    *DEVICES.read()
+}
+
+/// First bound device handled by `driver`.
+pub fn find(driver: &str) -> Option<Device> {
+    DEVICES
+        .read()
+        .iter()
+        .find(|device| device.driver == driver)
+        .copied()
+}
+
+fn bind(driver: &Driver, device: Device) {
+    match (driver.probe)(&device) {
+        Ok(()) => {
+            log!(
+                Info,
+                "Bound {} driver to {} at {:#x}",
+                driver.name,
+                device.name,
+                device.base
+            );
+            DEVICES.write().push(device);
+        }
+        Err(DeviceError::NotPresent) => {}
+        Err(e) => {
+            log!(
+                Warning,
+                "Failed to probe {} with {} driver: {:?}",
+                device.name,
+                driver.name,
+                e
+            );
+        }
+    }
+}
+
+fn device_from_node(driver: &Driver, node: &Node) -> Result<Device, DeviceError> {
+    let (base, size) = node.reg().next().ok_or(DeviceError::NoReg)?;
+    Ok(Device {
+        driver: driver.name,
+        name: node.name(),
+        base: base as usize,
+        size: size as usize,
+        irq: node.interrupts().next(),
+    })
+}
+
+/// Discover devices and bind drivers to them. Call once on hart 0,
+/// before `vm::init` so the kernel page table can map what we found.
+pub fn probe() {
+    let fdt = match fdt::boot_fdt() {
+        Some(fdt) => fdt,
+        None => {
+            log!(Warning, "No device tree, using default device addresses");
+            for (name, base, size, irq) in FALLBACK {
+                let driver = DRIVERS.iter().find(|d| d.name == name).unwrap();
+                let device = Device {
+                    driver: driver.name,
+                    name,
+                    base,
+                    size,
+                    irq,
+                };
+                bind(driver, device);
+            }
+            return;
+        }
+    };
+
+    for node in fdt.nodes() {
+        let disabled = node
+            .property("status")
+            .and_then(|p| p.as_str())
+            .map_or(false, |status| status != "okay" && status != "ok");
+        if disabled {
+            continue;
+        }
+        let driver = DRIVERS
+            .iter()
+            .find(|driver| node.compatible().any(|c| driver.compatible.contains(&c)));
+        if let Some(driver) = driver {
+            match device_from_node(driver, &node) {
+                Ok(device) => bind(driver, device),
+                Err(e) => log!(Warning, "Bad device node {}: {:?}", node.name(), e),
+            }
+        }
+    }
+}
@@ -1,10 +1,30 @@
 //! Core local interruptor (timer interrupts).
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use super::{Device, DeviceError, Driver};
 use crate::hw::param;
+#[cfg(feature = "sbi")]
 use crate::hw::riscv;
 
+/// Where the CLINT lives. Defaults to the QEMU virt address until probed,
+/// since the timer is armed in `_start` before we have looked at the device tree.
+static BASE: AtomicUsize = AtomicUsize::new(param::CLINT_BASE);
+
+pub static DRIVER: Driver = Driver {
+    name: "clint",
+    compatible: &["riscv,clint0", "sifive,clint0"],
+    probe,
+};
+
+fn probe(device: &Device) -> Result<(), DeviceError> {
+    BASE.store(device.base, Ordering::Relaxed);
+    Ok(())
+}
+
 /// Get the current CLINT time.
+#[cfg(not(feature = "sbi"))]
 pub fn read_mtime() -> u64 {
-    let base = param::CLINT_BASE as *mut u64;
+    let base = BASE.load(Ordering::Relaxed) as *mut u64;
     let mtime: u64;
     unsafe {
         mtime = base.byte_add(0xBFF8).read_volatile();
@@ -12,19 +32,35 @@ pub fn read_mtime() -> u64 {
     mtime
 }
 
-/// Set the CLINT MTIMECMP register.
+/// Get the current CLINT time. The CLINT belongs to the firmware when
+/// booted under OpenSBI, so go through the `time` CSR instead.
+#[cfg(feature = "sbi")]
+pub fn read_mtime() -> u64 {
+    riscv::read_time()
+}
+
+/// Set `hart`'s CLINT MTIMECMP register to `deadline`.
 /// When CLINT MTIME >= CLINT MTIMECMP it triggers
-/// a *machine*-mode interrupt.
+/// a *machine*-mode interrupt. `u64::MAX` never does.
 // mtimecmp reg is at base + 0x4000
 // mtime reg is base + 0xbff8
-pub fn set_mtimecmp(interval: u64) {
-    let hartid = riscv::read_mhartid() as usize;
-    let base = param::CLINT_BASE as *mut usize;
+pub fn set_mtimecmp(hart: usize, deadline: u64) {
+    let base = BASE.load(Ordering::Relaxed) as *mut u64;
     unsafe {
-        // One mtime register for all cores.
-        let mtime = base.byte_add(0xBFF8).read_volatile();
         // mtimecmp register per core.
-        base.byte_add(0x4000 + 8 * hartid)
-            .write_volatile(mtime + interval as usize);
+        base.byte_add(0x4000 + 8 * hart).write_volatile(deadline);
     }
 }
+
+/// Raise a machine software interrupt on `hart`.
+// msip reg is a u32 per core at base + 0x0
+pub fn set_msip(hart: usize) {
+    let base = BASE.load(Ordering::Relaxed) as *mut u32;
+    unsafe { base.byte_add(4 * hart).write_volatile(1) }
+}
+
+/// Acknowledge a machine software interrupt on `hart`.
+pub fn clear_msip(hart: usize) {
+    let base = BASE.load(Ordering::Relaxed) as *mut u32;
+    unsafe { base.byte_add(4 * hart).write_volatile(0) }
+}
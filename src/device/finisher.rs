@@ -0,0 +1,59 @@
+//! SiFive test device ("finisher"), power off and reboot on QEMU virt.
+// One u32 register: the low 16 bits say what to do, the high 16 bits are
+// the exit code QEMU hands to the host for FAIL.
+// Reference: qemu/hw/misc/sifive_test.c
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use super::{Device, DeviceError, Driver};
+
+const FINISHER_FAIL: u32 = 0x3333;
+const FINISHER_PASS: u32 = 0x5555;
+const FINISHER_RESET: u32 = 0x7777;
+
+/// Where the device lives, zero until probed. Without a device tree it is
+/// bound at `param::TEST_BASE`, see `device::probe`.
+static BASE: AtomicUsize = AtomicUsize::new(0);
+
+pub static DRIVER: Driver = Driver {
+    name: "finisher",
+    compatible: &["sifive,test1", "sifive,test0"],
+    probe,
+};
+
+fn probe(device: &Device) -> Result<(), DeviceError> {
+    BASE.store(device.base, Ordering::Relaxed);
+    Ok(())
+}
+
+/// Did we find the device? Nothing else here works without it, and it is
+/// only mapped once found.
+pub fn present() -> bool {
+    BASE.load(Ordering::Relaxed) != 0
+}
+
+fn write(value: u32) -> ! {
+    assert!(present(), "no SiFive test device");
+    let base = BASE.load(Ordering::Relaxed) as *mut u32;
+    unsafe { base.write_volatile(value) };
+    // QEMU stops on the write, real hardware may take a moment.
+    crate::ipi::halt()
+}
+
+/// Stop the machine, QEMU exits with status 0.
+pub fn poweroff() -> ! {
+    write(FINISHER_PASS)
+}
+
+/// Reset the machine, QEMU boots the kernel again.
+pub fn reboot() -> ! {
+    write(FINISHER_RESET)
+}
+
+/// Stop the machine, QEMU exits with status `code` (only the low 16 bits
+/// get through). Lets automated tests report pass (0) or fail to the host.
+pub fn exit_with_code(code: u16) -> ! {
+    match code {
+        0 => write(FINISHER_PASS),
+        code => write((code as u32) << 16 | FINISHER_FAIL),
+    }
+}
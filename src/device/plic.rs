@@ -0,0 +1,70 @@
+//! Platform level interrupt controller (external interrupts).
+// Register layout from the SiFive PLIC spec, as used by the QEMU virt machine:
+// https://github.com/riscv/riscv-plic-spec/blob/master/riscv-plic.adoc
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use super::{Device, DeviceError, Driver};
+use crate::hw::param;
+
+const PRIORITY: usize = 0x0; // u32 per source.
+const ENABLE: usize = 0x2000; // Bitmap per context, 0x80 apart.
+const THRESHOLD: usize = 0x200000; // Per context, 0x1000 apart.
+const CLAIM: usize = 0x200004; // Claim/complete, per context.
+
+/// Where the PLIC lives. Defaults to the QEMU virt address until probed.
+static BASE: AtomicUsize = AtomicUsize::new(param::PLIC_BASE);
+
+pub static DRIVER: Driver = Driver {
+    name: "plic",
+    compatible: &["riscv,plic0", "sifive,plic-1.0.0"],
+    probe,
+};
+
+fn probe(device: &Device) -> Result<(), DeviceError> {
+    BASE.store(device.base, Ordering::Relaxed);
+    Ok(())
+}
+
+fn reg(offset: usize) -> *mut u32 {
+    (BASE.load(Ordering::Relaxed) + offset) as *mut u32
+}
+
+/// QEMU virt gives each hart an M-mode context (2 * hart) followed by an
+/// S-mode context (2 * hart + 1). We only ever deal with the latter.
+fn s_context(hart: usize) -> usize {
+    2 * hart + 1
+}
+
+/// Set the priority of an interrupt source. Zero means never interrupt.
+pub fn set_priority(irq: u32, priority: u32) {
+    unsafe { reg(PRIORITY + 4 * irq as usize).write_volatile(priority) }
+}
+
+/// Let `irq` interrupt `hart` in supervisor mode.
+pub fn enable(hart: usize, irq: u32) {
+    let word = reg(ENABLE + 0x80 * s_context(hart) + 4 * (irq as usize / 32));
+    unsafe { word.write_volatile(word.read_volatile() | 1 << (irq % 32)) }
+}
+
+pub fn disable(hart: usize, irq: u32) {
+    let word = reg(ENABLE + 0x80 * s_context(hart) + 4 * (irq as usize / 32));
+    unsafe { word.write_volatile(word.read_volatile() & !(1 << (irq % 32))) }
+}
+
+/// Only interrupts with priority above `threshold` reach `hart`.
+pub fn set_threshold(hart: usize, threshold: u32) {
+    unsafe { reg(THRESHOLD + 0x1000 * s_context(hart)).write_volatile(threshold) }
+}
+
+/// Claim the highest priority pending interrupt for `hart`, if any.
+pub fn claim(hart: usize) -> Option<u32> {
+    match unsafe { reg(CLAIM + 0x1000 * s_context(hart)).read_volatile() } {
+        0 => None,
+        irq => Some(irq),
+    }
+}
+
+/// Tell the PLIC we are done handling a claimed interrupt.
+pub fn complete(hart: usize, irq: u32) {
+    unsafe { reg(CLAIM + 0x1000 * s_context(hart)).write_volatile(irq) }
+}
@@ -0,0 +1,34 @@
+//! Goldfish real time clock.
+// Reference:
+// https://android.googlesource.com/platform/external/qemu/+/master/docs/GOLDFISH-VIRTUAL-HARDWARE.TXT
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use super::{Device, DeviceError, Driver};
+use crate::hw::param;
+
+const TIME_LOW: usize = 0x00; // Reading this latches TIME_HIGH.
+const TIME_HIGH: usize = 0x04;
+
+/// Where the RTC lives. Defaults to the QEMU virt address until probed.
+static BASE: AtomicUsize = AtomicUsize::new(param::RTC_BASE);
+
+pub static DRIVER: Driver = Driver {
+    name: "rtc",
+    compatible: &["google,goldfish-rtc"],
+    probe,
+};
+
+fn probe(device: &Device) -> Result<(), DeviceError> {
+    BASE.store(device.base, Ordering::Relaxed);
+    Ok(())
+}
+
+/// Nanoseconds since the Unix epoch.
+pub fn read_time_ns() -> u64 {
+    let base = BASE.load(Ordering::Relaxed);
+    unsafe {
+        let low = ((base + TIME_LOW) as *const u32).read_volatile();
+        let high = ((base + TIME_HIGH) as *const u32).read_volatile();
+        (high as u64) << 32 | low as u64
+    }
+}
@@ -0,0 +1,34 @@
+//! ACLINT supervisor software interrupt device.
+// Spec: https://github.com/riscv/riscv-aclint/blob/main/riscv-aclint.adoc
+// QEMU virt only has one with `-machine virt,aclint=on`. Without it we raise
+// a machine software interrupt through the CLINT and let `m_handler`
+// forward it to S-mode.
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use super::{Device, DeviceError, Driver};
+
+/// Where the SSWI lives, zero until probed.
+static BASE: AtomicUsize = AtomicUsize::new(0);
+
+pub static DRIVER: Driver = Driver {
+    name: "sswi",
+    compatible: &["riscv,aclint-sswi"],
+    probe,
+};
+
+fn probe(device: &Device) -> Result<(), DeviceError> {
+    BASE.store(device.base, Ordering::Relaxed);
+    Ok(())
+}
+
+/// Did we find an SSWI device?
+pub fn present() -> bool {
+    BASE.load(Ordering::Relaxed) != 0
+}
+
+/// Set `hart`'s supervisor software interrupt pending bit.
+/// One u32 SETSSIP register per hart. The receiver clears `sip.SSIP` itself.
+pub fn send(hart: usize) {
+    let base = BASE.load(Ordering::Relaxed) as *mut u32;
+    unsafe { base.byte_add(4 * hart).write_volatile(1) }
+}
@@ -4,7 +4,9 @@
 // from https://github.com/sgmarz/osblog/tree/master/risc_v/src
 use core::fmt::Error;
 use core::fmt::Write;
+use core::sync::atomic::{AtomicUsize, Ordering};
 
+use super::{Device, DeviceError, Driver};
 use crate::hw::param::UART_BASE;
 use crate::lock::mutex::*;
 
@@ -13,7 +15,26 @@ const LCR: usize = 3; // Line Control Register (baud rate stuff)
 const FCR: usize = 2; // FIFO Control Register (see uart layout in reference)
                       //const LSR: usize = 2; // Line Status Register (ready to rx, ready to tx signals)
 
-pub static WRITER: Mutex<Uart> = Uart::new();
+pub static WRITER: Mutex<Uart> = Mutex::new(Uart {
+    base_address: UART_BASE,
+});
+
+/// Where the UART lives. Defaults to the QEMU virt address until probed.
+static BASE: AtomicUsize = AtomicUsize::new(UART_BASE);
+
+pub static DRIVER: Driver = Driver {
+    name: "uart",
+    compatible: &["ns16550a", "ns16550"],
+    probe,
+};
+
+fn probe(device: &Device) -> Result<(), DeviceError> {
+    if BASE.swap(device.base, Ordering::Relaxed) != device.base {
+        // Moved, so set the new one up.
+        Uart::init();
+    }
+    Ok(())
+}
 
 pub struct Uart {
     base_address: usize,
@@ -31,7 +52,7 @@ impl Write for Uart {
 impl Uart {
     pub fn init() {
         // https://mth.st/blog/riscv-qemu/AN-491.pdf <-- inclues 16650A ref
-        let ptr = UART_BASE as *mut u8;
+        let ptr = BASE.load(Ordering::Relaxed) as *mut u8;
         // Basic semantics:
         // `ptr` is a memory address.
         // We want to write certain values to 'registers' located
@@ -56,9 +77,9 @@ impl Uart {
         }
     }
 
-    pub const fn new() -> Mutex<Self> {
+    pub fn new() -> Mutex<Self> {
         Mutex::new(Uart {
-            base_address: UART_BASE,
+            base_address: BASE.load(Ordering::Relaxed),
         })
     }
 
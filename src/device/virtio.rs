@@ -0,0 +1,50 @@
+//! Virtio over MMIO transport discovery.
+// Reference: Virtio 1.1 spec, section 4.2.2 "MMIO Device Register Layout".
+// https://docs.oasis-open.org/virtio/virtio/v1.1/virtio-v1.1.html
+//
+// QEMU virt always provides eight virtio-mmio slots, most of them empty. We
+// only bind the slots that have a device behind them. Actual device drivers
+// (block, net, ...) can look these up with `device::devices()`.
+use super::{Device, DeviceError, Driver};
+
+const MAGIC: usize = 0x000; // "virt"
+const VERSION: usize = 0x004;
+const DEVICE_ID: usize = 0x008;
+const VENDOR_ID: usize = 0x00c;
+
+const VIRTIO_MAGIC: u32 = 0x74726976;
+
+pub static DRIVER: Driver = Driver {
+    name: "virtio-mmio",
+    compatible: &["virtio,mmio"],
+    probe,
+};
+
+fn read(base: usize, offset: usize) -> u32 {
+    unsafe { ((base + offset) as *const u32).read_volatile() }
+}
+
+/// Virtio device type of the device at `base`, zero for an empty slot.
+pub fn device_id(base: usize) -> u32 {
+    read(base, DEVICE_ID)
+}
+
+fn probe(device: &Device) -> Result<(), DeviceError> {
+    if read(device.base, MAGIC) != VIRTIO_MAGIC {
+        return Err(DeviceError::Unsupported);
+    }
+    match device_id(device.base) {
+        0 => Err(DeviceError::NotPresent),
+        id => {
+            log!(
+                Debug,
+                "virtio-mmio v{} device type {} (vendor {:#x}) at {:#x}",
+                read(device.base, VERSION),
+                id,
+                read(device.base, VENDOR_ID),
+                device.base
+            );
+            Ok(())
+        }
+    }
+}
@@ -1,30 +1,308 @@
 //! Target-hardware parameters and utilities.
+pub mod fdt;
 pub mod param;
 pub mod riscv;
+pub mod sbi;
 
+use alloc::boxed::Box;
+use core::cell::{Cell, RefCell, RefMut, UnsafeCell};
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+#[cfg(not(feature = "sbi"))]
 use crate::device::clint;
+#[cfg(not(feature = "sbi"))]
 use crate::trap;
 use crate::vm::process::Process;
 use riscv::*;
 
+/// Cached result of `nharts()`, zero until first asked.
+static NHARTS: AtomicUsize = AtomicUsize::new(0);
+
+/// One record per hart, each only ever touched by its own hart.
+/// `hart_init` points the hart's `tp` at its entry.
+static mut HARTS: [Hart; param::MAX_HARTS] = [Hart::INIT; param::MAX_HARTS];
+
 /// Callee saved registers.
+/// `asm/switch.s` saves ra, sp, s0-s11 into the first 14 slots, in that order.
+#[repr(C)]
 pub struct HartContext {
     regs: [usize; 32],
 }
 
+impl HartContext {
+    pub const fn new() -> Self {
+        HartContext { regs: [0; 32] }
+    }
+
+    /// A context that `swtch` "returns" into `entry` from, running on the
+    /// stack that ends at `stack_top`. s0 is zero, which ends backtraces.
+    pub fn entry(entry: usize, stack_top: usize) -> Self {
+        let mut ctx = HartContext::new();
+        ctx.regs[0] = entry;
+        ctx.regs[1] = stack_top;
+        ctx
+    }
+}
+
+extern "C" {
+    /// Save the current context into `old` and switch to `new`. Returns
+    /// when something switches back to `old`. See `asm/switch.s`.
+    pub fn swtch(old: *mut HartContext, new: *const HartContext);
+}
+
 /// Representation of riscv hart.
+/// Only ever touched by its own hart, but from nested code (trap handlers,
+/// `push_off` inside the scheduler), so everything is behind a cell and
+/// `this_hart()` hands out shared references.
 pub struct Hart {
-    id: usize,
-    process: Process,
-    ctx_regs: HartContext,
+    id: Cell<usize>,
+    /// Process currently running on this hart.
+    process: RefCell<Option<Box<Process>>>,
+    /// How many interrupt disabling critical sections we are nested in.
+    irq_depth: Cell<usize>,
+    /// Whether supervisor interrupts were on before the outermost one.
+    irq_enabled: Cell<bool>,
+    /// How many supervisor trap handlers we are nested in.
+    trap_depth: Cell<usize>,
+    /// Scheduler context, switched back to when a process gives up the hart.
+    ctx_regs: UnsafeCell<HartContext>,
+}
+
+impl Hart {
+    #[allow(clippy::declare_interior_mutable_const)]
+    const INIT: Hart = Hart {
+        id: Cell::new(0),
+        process: RefCell::new(None),
+        irq_depth: Cell::new(0),
+        irq_enabled: Cell::new(false),
+        trap_depth: Cell::new(0),
+        ctx_regs: UnsafeCell::new(HartContext::new()),
+    };
+
+    pub fn id(&self) -> usize {
+        self.id.get()
+    }
+
+    /// The running process. Panics if it is already borrowed, drop the
+    /// guard before switching away.
+    pub fn process(&self) -> Option<RefMut<Process>> {
+        RefMut::filter_map(self.process.borrow_mut(), |p| p.as_deref_mut()).ok()
+    }
+
+    /// Run `f` on the running process, unless there is none or it is
+    /// borrowed already. For the panic and backtrace paths, which may come
+    /// in while it is.
+    pub fn peek_process<R>(&self, f: impl FnOnce(&Process) -> R) -> Option<R> {
+        let process = self.process.try_borrow().ok()?;
+        process.as_deref().map(f)
+    }
+
+    /// Is there a running process? Doesn't borrow it, so a trap handler can
+    /// ask while the code it interrupted has it borrowed.
+    pub fn has_process(&self) -> bool {
+        self.process.try_borrow().map_or(true, |p| p.is_some())
+    }
+
+    /// Install `process` as the running process, handing back the old one.
+    pub fn set_process(&self, process: Option<Box<Process>>) -> Option<Box<Process>> {
+        self.process.replace(process)
+    }
+
+    pub fn irq_depth(&self) -> usize {
+        self.irq_depth.get()
+    }
+
+    /// Whether interrupts come back on at the outermost `pop_off`. Saved and
+    /// restored around context switches, see `sched`.
+    pub fn irq_enabled(&self) -> bool {
+        self.irq_enabled.get()
+    }
+
+    pub fn set_irq_enabled(&self, enabled: bool) {
+        self.irq_enabled.set(enabled);
+    }
+
+    /// For `swtch` only.
+    pub fn context(&self) -> *mut HartContext {
+        self.ctx_regs.get()
+    }
+
+    /// Whether we are in a trap handler, as opposed to the code it interrupted.
+    pub fn in_trap(&self) -> bool {
+        self.trap_depth.get() > 0
+    }
+
+    pub fn enter_trap(&self) {
+        self.trap_depth.set(self.trap_depth.get() + 1);
+    }
+
+    pub fn leave_trap(&self) {
+        self.trap_depth.set(self.trap_depth.get() - 1);
+    }
+}
+
+/// Set up this hart's `Hart` record and point `tp` at it.
+/// From here on `this_hart()` and `hartid()` work. Call first thing in `main`,
+/// while `tp` still holds the hartid `_start` put there.
+pub fn hart_init(hartid: usize) {
+    assert!(
+        hartid < param::MAX_HARTS,
+        "hart {} has no Hart record, raise param::MAX_HARTS",
+        hartid
+    );
+    unsafe {
+        let hart = &*core::ptr::addr_of!(HARTS[hartid]);
+        hart.id.set(hartid);
+        write_tp(hart as *const Hart as u64);
+    }
+}
+
+/// The calling hart's `Hart` record.
+pub fn this_hart() -> &'static Hart {
+    let tp = read_tp() as usize;
+    unsafe {
+        // Before `hart_init` tp is still the plain hartid from `_start`.
+        if tp < param::MAX_HARTS {
+            &*core::ptr::addr_of!(HARTS[tp])
+        } else {
+            &*(tp as *const Hart)
+        }
+    }
+}
+
+/// Disable interrupts on this hart, nesting. Interrupts come back on at
+/// the matching outermost `pop_off`, if they were on to begin with.
+/// Reference: xv6-riscv/kernel/spinlock.c
+pub fn push_off() {
+    let enabled = intr_get();
+    intr_off();
+    let hart = this_hart();
+    if hart.irq_depth() == 0 {
+        hart.set_irq_enabled(enabled);
+    }
+    hart.irq_depth.set(hart.irq_depth() + 1);
+}
+
+/// Undo one `push_off`.
+pub fn pop_off() {
+    assert!(!intr_get(), "pop_off with interrupts on");
+    let hart = this_hart();
+    assert!(hart.irq_depth() > 0, "pop_off without push_off");
+    hart.irq_depth.set(hart.irq_depth() - 1);
+    if hart.irq_depth() == 0 && hart.irq_enabled() {
+        intr_on();
+    }
+}
+
+/// Id of the calling hart.
+pub fn hartid() -> usize {
+    let tp = read_tp() as usize;
+    // Before `hart_init` tp is still the plain hartid from `_start`.
+    if tp < param::MAX_HARTS {
+        tp
+    } else {
+        this_hart().id()
+    }
+}
+
+/// Number of harts the kernel runs on: the enabled cpus in the device tree,
+/// capped at the number `kernel.ld` has stacks for (`param::max_harts()`).
+/// Hart ids are assumed to be `0..nharts()`, as they are on QEMU virt.
+pub fn nharts() -> usize {
+    match NHARTS.load(Ordering::Relaxed) {
+        0 => {
+            let found = match fdt::boot_fdt().and_then(|fdt| fdt.find_node("/cpus")) {
+                Some(cpus) => cpus
+                    .children()
+                    .filter(|cpu| {
+                        cpu.property("device_type").and_then(|p| p.as_str()) == Some("cpu")
+                            && cpu.property("status").and_then(|p| p.as_str()) != Some("disabled")
+                    })
+                    .count(),
+                None => param::NHART,
+            };
+            let n = found.clamp(1, param::max_harts());
+            if n < found {
+                log!(
+                    Warning,
+                    "Found {} harts but only have stacks for {}, raise MAX_HARTS in kernel.ld",
+                    found,
+                    n
+                );
+            }
+            NHARTS.store(n, Ordering::Relaxed);
+            n
+        }
+        n => n,
+    }
+}
+
+/// A set of harts, bit i standing for hart i.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct HartSet(usize);
+
+impl HartSet {
+    pub const fn empty() -> Self {
+        HartSet(0)
+    }
+
+    pub const fn from_bits(bits: usize) -> Self {
+        HartSet(bits)
+    }
+
+    pub fn single(hart: usize) -> Self {
+        HartSet(1 << hart)
+    }
+
+    /// Every hart the kernel runs on.
+    pub fn all() -> Self {
+        HartSet(usize::MAX >> (usize::BITS as usize - nharts()))
+    }
+
+    /// Every hart but the caller.
+    pub fn others() -> Self {
+        let mut set = Self::all();
+        set.remove(hartid());
+        set
+    }
+
+    pub fn bits(&self) -> usize {
+        self.0
+    }
+
+    pub fn insert(&mut self, hart: usize) {
+        self.0 |= 1 << hart;
+    }
+
+    pub fn remove(&mut self, hart: usize) {
+        self.0 &= !(1 << hart);
+    }
+
+    pub fn contains(&self, hart: usize) -> bool {
+        self.0 & (1 << hart) != 0
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0 == 0
+    }
+
+    pub fn len(&self) -> usize {
+        self.0.count_ones() as usize
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = usize> {
+        let bits = self.0;
+        (0..usize::BITS as usize).filter(move |hart| bits & (1 << hart) != 0)
+    }
 }
 
 /// Set up and enable the core local interrupt controller on each hart.
 /// We write the machine mode trap vector register (mtvec) with the address
 /// of our `src/asm` trap handler function.
+#[cfg(not(feature = "sbi"))]
 pub fn timerinit() {
-    let interval = 10_000_000; // May want to speed this up in the future.
-    clint::set_mtimecmp(interval);
+    // Nothing is due until `timer` arms something.
+    clint::set_mtimecmp(read_mhartid() as usize, u64::MAX);
 
     // Set the machine trap vector to hold fn ptr to timervec.
     let timervec_fn = trap::__mtrapvec;
@@ -35,7 +313,42 @@ pub fn timerinit() {
     mstatus |= MSTATUS_MIE;
     write_mstatus(mstatus);
 
-    // Enable machine-mode timer interrupts.
-    let mie = read_mie() | MIE_MTIE;
+    // Enable machine-mode timer and software interrupts. The latter
+    // carry IPIs, see `ipi`.
+    let mie = read_mie() | MIE_MTIE | MIE_MSIE;
     write_mie(mie);
 }
+
+/// Enable the supervisor timer on each hart. The firmware owns the CLINT
+/// and M-mode trap vector, and hands us timer interrupts directly.
+#[cfg(feature = "sbi")]
+pub fn timerinit() {
+    // Nothing is due until `timer` arms something.
+    let _ = sbi::set_timer(u64::MAX);
+    write_sie(read_sie() | SIE_STIE);
+}
+
+extern "C" {
+    /// Kernel entry point in `asm/entry.s`.
+    fn _entry();
+}
+
+/// Under OpenSBI only one hart (picked by the firmware) enters the kernel.
+/// The first hart to get here starts every other hart at `_entry` through
+/// the HSM extension, passing the device tree along as the opaque argument
+/// so secondary harts come in just like the first one.
+#[cfg(feature = "sbi")]
+pub fn start_harts(hartid: usize, dtb: usize) {
+    use core::sync::atomic::AtomicBool;
+    static STARTED: AtomicBool = AtomicBool::new(false);
+
+    if STARTED.swap(true, Ordering::AcqRel) {
+        return;
+    }
+    for hart in (0..nharts()).filter(|&hart| hart != hartid) {
+        match sbi::hart_start(hart, _entry as usize, dtb) {
+            Ok(_) | Err(sbi::SbiError::AlreadyAvailable) => {}
+            Err(e) => log!(Warning, "Failed to start hart {}: {:?}", hart, e),
+        }
+    }
+}
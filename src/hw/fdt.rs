@@ -0,0 +1,534 @@
+//! Flattened device tree (DTB) parsing.
+// Reference:
+// https://devicetree-specification.readthedocs.io/en/latest/chapter5-flattened-format.html
+//
+// Everything in the blob is big endian. The blob is laid out as:
+// ┌────────────────┐
+// │ header         │
+// ├────────────────┤
+// │ mem rsvmap     │ (u64 address, u64 size) pairs, terminated by (0, 0)
+// ├────────────────┤
+// │ struct block   │ FDT_BEGIN_NODE / FDT_PROP / FDT_END_NODE tokens
+// ├────────────────┤
+// │ strings block  │ nul terminated property names
+// └────────────────┘
+//
+// Nothing here allocates, since we need the tree before the page pool
+// and kernel heap exist.
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+const FDT_MAGIC: u32 = 0xd00dfeed;
+const FDT_BEGIN_NODE: u32 = 0x1;
+const FDT_END_NODE: u32 = 0x2;
+const FDT_PROP: u32 = 0x3;
+const FDT_NOP: u32 = 0x4;
+const FDT_END: u32 = 0x9;
+
+/// Physical address of the blob handed to us by the previous boot stage in a1.
+static BOOT_FDT: AtomicUsize = AtomicUsize::new(0);
+
+#[derive(Debug)]
+pub enum FdtError {
+    NullBlob,
+    BadMagic,
+    BadVersion,
+}
+
+/// A parsed device tree header plus the blob it describes.
+#[derive(Copy, Clone)]
+pub struct Fdt {
+    blob: &'static [u8],
+    off_struct: usize,
+    off_strings: usize,
+    off_rsvmap: usize,
+}
+
+/// A node in the tree. `addr_cells` and `size_cells` are the parent's
+/// `#address-cells` and `#size-cells`, needed to decode this node's `reg`.
+#[derive(Copy, Clone)]
+pub struct Node {
+    fdt: Fdt,
+    name: &'static str,
+    body: usize, // Struct block offset of the first token after the name.
+    addr_cells: usize,
+    size_cells: usize,
+}
+
+#[derive(Copy, Clone)]
+pub struct Property {
+    pub name: &'static str,
+    pub value: &'static [u8],
+}
+
+fn be32(bytes: &[u8], offset: usize) -> u32 {
+    u32::from_be_bytes(bytes[offset..offset + 4].try_into().unwrap())
+}
+
+fn be64(bytes: &[u8], offset: usize) -> u64 {
+    u64::from_be_bytes(bytes[offset..offset + 8].try_into().unwrap())
+}
+
+fn align4(offset: usize) -> usize {
+    (offset + 3) & !3
+}
+
+/// Read a nul terminated string starting at `offset`.
+fn cstr(bytes: &'static [u8], offset: usize) -> &'static str {
+    let len = bytes[offset..].iter().position(|&b| b == 0).unwrap_or(0);
+    core::str::from_utf8(&bytes[offset..offset + len]).unwrap_or("")
+}
+
+/// Record the blob address passed in a1 at boot. Called from `_start`.
+pub fn set_boot_fdt(addr: usize) {
+    BOOT_FDT.store(addr, Ordering::Relaxed);
+}
+
+/// The device tree we were booted with, if there is a valid one.
+pub fn boot_fdt() -> Option<Fdt> {
+    unsafe { Fdt::from_addr(BOOT_FDT.load(Ordering::Relaxed)).ok() }
+}
+
+impl Fdt {
+    /// Parse the header of the blob at `addr`. The blob must stay
+    /// in place and unmodified for the lifetime of the kernel.
+    pub unsafe fn from_addr(addr: usize) -> Result<Self, FdtError> {
+        if addr == 0 {
+            return Err(FdtError::NullBlob);
+        }
+        let header = core::slice::from_raw_parts(addr as *const u8, 40);
+        if be32(header, 0) != FDT_MAGIC {
+            return Err(FdtError::BadMagic);
+        }
+        // last_comp_version: refuse blobs that we (version 17) can't read.
+        if be32(header, 24) > 17 {
+            return Err(FdtError::BadVersion);
+        }
+        let total = be32(header, 4) as usize;
+        Ok(Fdt {
+            blob: core::slice::from_raw_parts(addr as *const u8, total),
+            off_struct: be32(header, 8) as usize,
+            off_strings: be32(header, 12) as usize,
+            off_rsvmap: be32(header, 16) as usize,
+        })
+    }
+
+    /// Physical address of the blob.
+    pub fn addr(&self) -> usize {
+        self.blob.as_ptr().addr()
+    }
+
+    /// Size of the blob in bytes.
+    pub fn total_size(&self) -> usize {
+        self.blob.len()
+    }
+
+    /// Entries of the memory reservation block as (address, size).
+    pub fn mem_reservations(&self) -> MemReservations {
+        MemReservations {
+            fdt: *self,
+            offset: self.off_rsvmap,
+        }
+    }
+
+    fn token(&self, offset: usize) -> u32 {
+        be32(self.blob, self.off_struct + offset)
+    }
+
+    fn string(&self, offset: usize) -> &'static str {
+        cstr(self.blob, self.off_strings + offset)
+    }
+
+    /// The root node.
+    pub fn root(&self) -> Node {
+        let mut offset = 0;
+        while self.token(offset) == FDT_NOP {
+            offset += 4;
+        }
+        assert_eq!(self.token(offset), FDT_BEGIN_NODE, "fdt: no root node");
+        // Root starts with the default cell sizes from the spec.
+        Node::at(*self, offset, 2, 1)
+    }
+
+    /// Every node in the tree, depth first, starting with the root.
+    pub fn nodes(&self) -> Nodes {
+        let root = self.root();
+        let mut cells = [(2, 1); MAX_DEPTH];
+        cells[1] = root.child_cells();
+        Nodes {
+            fdt: *self,
+            offset: root.body,
+            depth: 1,
+            cells,
+            root: Some(root),
+        }
+    }
+
+    /// Every node whose `compatible` list contains `compat`.
+    pub fn find_compatible<'a>(&self, compat: &'a str) -> impl Iterator<Item = Node> + 'a {
+        self.nodes().filter(move |node| node.is_compatible(compat))
+    }
+
+    /// Find the node with the given `phandle`.
+    pub fn find_phandle(&self, phandle: u32) -> Option<Node> {
+        self.nodes().find(|node| {
+            node.property("phandle")
+                .or_else(|| node.property("linux,phandle"))
+                .and_then(|p| p.as_u32())
+                == Some(phandle)
+        })
+    }
+
+    /// Look up a node by absolute path, like `/soc/uart@10000000`.
+    /// A component without a unit address also matches nodes with one,
+    /// so `/memory` finds `/memory@80000000`.
+    pub fn find_node(&self, path: &str) -> Option<Node> {
+        let mut node = self.root();
+        for part in path.split('/').filter(|part| !part.is_empty()) {
+            node = node.children().find(|child| child.name_matches(part))?;
+        }
+        Some(node)
+    }
+}
+
+/// Iterator over the memory reservation block.
+pub struct MemReservations {
+    fdt: Fdt,
+    offset: usize,
+}
+
+impl Iterator for MemReservations {
+    type Item = (u64, u64);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let addr = be64(self.fdt.blob, self.offset);
+        let size = be64(self.fdt.blob, self.offset + 8);
+        if addr == 0 && size == 0 {
+            None
+        } else {
+            self.offset += 16;
+            Some((addr, size))
+        }
+    }
+}
+
+impl Node {
+    /// `offset` points at this node's FDT_BEGIN_NODE token.
+    fn at(fdt: Fdt, offset: usize, addr_cells: usize, size_cells: usize) -> Self {
+        let name_start = fdt.off_struct + offset + 4;
+        let name = cstr(fdt.blob, name_start);
+        Node {
+            fdt,
+            name,
+            body: align4(offset + 4 + name.len() + 1),
+            addr_cells,
+            size_cells,
+        }
+    }
+
+    /// Full node name, including any unit address (`memory@80000000`).
+    pub fn name(&self) -> &'static str {
+        self.name
+    }
+
+    /// Node name up to the `@`.
+    pub fn base_name(&self) -> &'static str {
+        self.name.split('@').next().unwrap_or("")
+    }
+
+    fn name_matches(&self, part: &str) -> bool {
+        self.name == part || (!part.contains('@') && self.base_name() == part)
+    }
+
+    pub fn properties(&self) -> Properties {
+        Properties {
+            fdt: self.fdt,
+            offset: self.body,
+        }
+    }
+
+    pub fn property(&self, name: &str) -> Option<Property> {
+        self.properties().find(|prop| prop.name == name)
+    }
+
+    /// `#address-cells` and `#size-cells` this node declares for its children.
+    fn child_cells(&self) -> (usize, usize) {
+        let addr = self.property("#address-cells").and_then(|p| p.as_u32());
+        let size = self.property("#size-cells").and_then(|p| p.as_u32());
+        (addr.unwrap_or(2) as usize, size.unwrap_or(1) as usize)
+    }
+
+    pub fn children(&self) -> Children {
+        let (addr_cells, size_cells) = self.child_cells();
+        // Skip past our own properties to the first child.
+        let mut props = self.properties();
+        while props.next().is_some() {}
+        Children {
+            fdt: self.fdt,
+            offset: props.offset,
+            addr_cells,
+            size_cells,
+        }
+    }
+
+    /// Strings of the `compatible` property, most specific first.
+    pub fn compatible(&self) -> StrList {
+        StrList {
+            value: self.property("compatible").map(|p| p.value).unwrap_or(&[]),
+        }
+    }
+
+    pub fn is_compatible(&self, compat: &str) -> bool {
+        self.compatible().any(|c| c == compat)
+    }
+
+    /// Interrupt numbers from the `interrupts` property. We only report the
+    /// first cell of each specifier, which is the source number for the
+    /// PLIC and CLINT. The specifier size comes from the interrupt parent's
+    /// `#interrupt-cells`.
+    pub fn interrupts(&self) -> Interrupts {
+        let cells = self
+            .property("interrupt-parent")
+            .and_then(|p| p.as_u32())
+            .and_then(|phandle| self.fdt.find_phandle(phandle))
+            .and_then(|parent| parent.property("#interrupt-cells"))
+            .and_then(|p| p.as_u32())
+            .unwrap_or(1) as usize;
+        Interrupts {
+            value: self.property("interrupts").map(|p| p.value).unwrap_or(&[]),
+            cells,
+        }
+    }
+
+    /// Decode the `reg` property as (address, size) pairs.
+    pub fn reg(&self) -> Reg {
+        Reg {
+            value: self.property("reg").map(|p| p.value).unwrap_or(&[]),
+            addr_cells: self.addr_cells,
+            size_cells: self.size_cells,
+        }
+    }
+}
+
+/// Iterator over a node's properties.
+pub struct Properties {
+    fdt: Fdt,
+    offset: usize,
+}
+
+impl Iterator for Properties {
+    type Item = Property;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            match self.fdt.token(self.offset) {
+                FDT_NOP => self.offset += 4,
+                FDT_PROP => {
+                    let len = self.fdt.token(self.offset + 4) as usize;
+                    let name_off = self.fdt.token(self.offset + 8) as usize;
+                    let start = self.fdt.off_struct + self.offset + 12;
+                    self.offset = align4(self.offset + 12 + len);
+                    return Some(Property {
+                        name: self.fdt.string(name_off),
+                        value: &self.fdt.blob[start..start + len],
+                    });
+                }
+                _ => return None,
+            }
+        }
+    }
+}
+
+/// Iterator over a node's direct children.
+pub struct Children {
+    fdt: Fdt,
+    offset: usize,
+    addr_cells: usize,
+    size_cells: usize,
+}
+
+impl Children {
+    /// Skip the subtree starting at the FDT_BEGIN_NODE at `self.offset`.
+    fn skip_subtree(&mut self) {
+        let mut depth = 0;
+        loop {
+            match self.fdt.token(self.offset) {
+                FDT_BEGIN_NODE => {
+                    let name = cstr(self.fdt.blob, self.fdt.off_struct + self.offset + 4);
+                    self.offset = align4(self.offset + 4 + name.len() + 1);
+                    depth += 1;
+                }
+                FDT_END_NODE => {
+                    self.offset += 4;
+                    depth -= 1;
+                    if depth == 0 {
+                        return;
+                    }
+                }
+                FDT_PROP => {
+                    let len = self.fdt.token(self.offset + 4) as usize;
+                    self.offset = align4(self.offset + 12 + len);
+                }
+                FDT_NOP => self.offset += 4,
+                _ => return, // FDT_END or garbage, bail.
+            }
+        }
+    }
+}
+
+impl Iterator for Children {
+    type Item = Node;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            match self.fdt.token(self.offset) {
+                FDT_NOP => self.offset += 4,
+                FDT_BEGIN_NODE => {
+                    let node = Node::at(self.fdt, self.offset, self.addr_cells, self.size_cells);
+                    self.skip_subtree();
+                    return Some(node);
+                }
+                // FDT_END_NODE of the parent, or FDT_END.
+                _ => return None,
+            }
+        }
+    }
+}
+
+/// Deepest nesting `Fdt::nodes` will descend to.
+const MAX_DEPTH: usize = 16;
+
+/// Depth first iterator over every node.
+pub struct Nodes {
+    fdt: Fdt,
+    offset: usize,
+    depth: usize,
+    // Child (#address-cells, #size-cells) of the open node at each depth.
+    cells: [(usize, usize); MAX_DEPTH],
+    root: Option<Node>,
+}
+
+impl Iterator for Nodes {
+    type Item = Node;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if let Some(root) = self.root.take() {
+            return Some(root);
+        }
+        loop {
+            match self.fdt.token(self.offset) {
+                FDT_BEGIN_NODE => {
+                    let (addr_cells, size_cells) = self.cells[self.depth];
+                    let node = Node::at(self.fdt, self.offset, addr_cells, size_cells);
+                    self.offset = node.body;
+                    self.depth += 1;
+                    assert!(self.depth < MAX_DEPTH, "fdt: tree too deep");
+                    self.cells[self.depth] = node.child_cells();
+                    return Some(node);
+                }
+                FDT_END_NODE => {
+                    self.offset += 4;
+                    self.depth -= 1;
+                    if self.depth == 0 {
+                        return None;
+                    }
+                }
+                FDT_PROP => {
+                    let len = self.fdt.token(self.offset + 4) as usize;
+                    self.offset = align4(self.offset + 12 + len);
+                }
+                FDT_NOP => self.offset += 4,
+                _ => return None, // FDT_END
+            }
+        }
+    }
+}
+
+/// Iterator over a nul separated string list property like `compatible`.
+pub struct StrList {
+    value: &'static [u8],
+}
+
+impl Iterator for StrList {
+    type Item = &'static str;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.value.is_empty() {
+            return None;
+        }
+        let len = self
+            .value
+            .iter()
+            .position(|&b| b == 0)
+            .unwrap_or(self.value.len());
+        let out = core::str::from_utf8(&self.value[..len]).unwrap_or("");
+        self.value = &self.value[(len + 1).min(self.value.len())..];
+        Some(out)
+    }
+}
+
+/// Iterator over the interrupt numbers of an `interrupts` property.
+pub struct Interrupts {
+    value: &'static [u8],
+    cells: usize,
+}
+
+impl Iterator for Interrupts {
+    type Item = u32;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let entry = self.cells * 4;
+        if entry == 0 || self.value.len() < entry {
+            return None;
+        }
+        let irq = be32(self.value, 0);
+        self.value = &self.value[entry..];
+        Some(irq)
+    }
+}
+
+/// Iterator over the (address, size) pairs of a `reg` property.
+pub struct Reg {
+    value: &'static [u8],
+    addr_cells: usize,
+    size_cells: usize,
+}
+
+/// Read a value made of `cells` big endian u32 cells.
+fn read_cells(value: &[u8], cells: usize) -> u64 {
+    (0..cells).fold(0, |acc, i| (acc << 32) | be32(value, i * 4) as u64)
+}
+
+impl Iterator for Reg {
+    type Item = (u64, u64);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let entry = (self.addr_cells + self.size_cells) * 4;
+        if entry == 0 || self.value.len() < entry {
+            return None;
+        }
+        let addr = read_cells(self.value, self.addr_cells);
+        let size = read_cells(&self.value[self.addr_cells * 4..], self.size_cells);
+        self.value = &self.value[entry..];
+        Some((addr, size))
+    }
+}
+
+impl Property {
+    pub fn as_u32(&self) -> Option<u32> {
+        (self.value.len() >= 4).then(|| be32(self.value, 0))
+    }
+
+    pub fn as_u64(&self) -> Option<u64> {
+        match self.value.len() {
+            4 => Some(be32(self.value, 0) as u64),
+            8 => Some(be64(self.value, 0)),
+            _ => None,
+        }
+    }
+
+    /// Property value as a single string (without the trailing nul).
+    pub fn as_str(&self) -> Option<&'static str> {
+        let bytes = self.value.strip_suffix(&[0]).unwrap_or(self.value);
+        core::str::from_utf8(bytes).ok()
+    }
+}
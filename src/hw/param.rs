@@ -27,6 +27,9 @@
 
 use core::ptr::addr_of_mut;
 
+// These are only defaults, `device::probe` takes the real addresses from the
+// device tree when there is one.
+//
 // NOTE:
 // We can't just use link_name for linker symbols, cause they don't
 // bind correctly for some reason.
@@ -45,6 +48,7 @@ extern "C" {
     static mut _stacks_end: usize;
     static mut _intstacks_start: usize;
     static mut _intstacks_end: usize;
+    static mut _max_harts: usize;
 }
 
 /// CLINT base address.
@@ -53,8 +57,22 @@ pub const CLINT_BASE: usize = 0x2000000;
 /// UART base adderss.
 pub const UART_BASE: usize = 0x10000000;
 
+/// PLIC base address.
+pub const PLIC_BASE: usize = 0xc000000;
+
+/// SiFive test device (power off and reset) base address.
+pub const TEST_BASE: usize = 0x100000;
+
+/// Goldfish RTC base address.
+pub const RTC_BASE: usize = 0x101000;
+
 /// Start of kernel memory (first .text section goes here).
+#[cfg(not(feature = "sbi"))]
 pub const DRAM_BASE: *mut usize = 0x80000000 as *mut usize;
+/// Start of kernel memory (first .text section goes here). OpenSBI
+/// keeps the first 2M of RAM for itself.
+#[cfg(feature = "sbi")]
+pub const DRAM_BASE: *mut usize = 0x80200000 as *mut usize;
 
 pub fn text_end() -> *mut usize {
     unsafe { addr_of_mut!(_text_end) }
@@ -92,13 +110,44 @@ pub fn intstacks_end() -> *mut usize {
     unsafe { addr_of_mut!(_intstacks_end) }
 }
 
+/// Most harts `kernel.ld` reserves stacks for. This is a linker
+/// constant, so its value is the symbol's "address".
+pub fn max_harts() -> usize {
+    unsafe { addr_of_mut!(_max_harts).addr() }
+}
+
+/// Most harts we keep a `hw::Hart` record for.
+/// Keep this in step with MAX_HARTS in `kernel-common.ld`.
+pub const MAX_HARTS: usize = 8;
+
+/// Boot stack of `hart` as (bottom, top). Two pages below
+/// `_stacks_end - hart * 3 pages`, under a guard page.
+pub fn hart_stack(hart: usize) -> (*mut usize, *mut usize) {
+    let top = unsafe { stacks_end().byte_sub(hart * 3 * PAGE_SIZE) };
+    (unsafe { top.byte_sub(2 * PAGE_SIZE) }, top)
+}
+
+/// Interrupt stacks of `hart` as (machine mode top, supervisor mode top),
+/// each one page below its top and above a guard page. These are what
+/// `entry.s` loads into mscratch and sscratch.
+pub fn hart_intstacks(hart: usize) -> (*mut usize, *mut usize) {
+    let m_top = unsafe { intstacks_end().byte_sub(hart * 4 * PAGE_SIZE) };
+    (m_top, unsafe { m_top.byte_sub(2 * PAGE_SIZE) })
+}
+
 pub fn dram_end() -> *mut usize {
     unsafe { addr_of_mut!(_memory_end) }
 }
 
 pub static PAGE_SIZE: usize = 4096;
 
+/// mtime ticks per second on QEMU virt. Only a fallback, `time::init` takes
+/// the device tree's timebase-frequency.
+pub const TIMEBASE_HZ: u64 = 10_000_000;
+
 // Run parameters
+/// Number of harts assumed when there is no device tree to ask.
+/// See `hw::nharts()`.
 pub const NHART: usize = 2;
 
 // Unnecessary.
@@ -7,6 +7,10 @@ pub const MSTATUS_MPP_M: u64 = 3 << 11; // Machine
 pub const MSTATUS_MPP_S: u64 = 1 << 11; // Supervisor
 pub const MSTATUS_MPP_U: u64 = 0 << 11; // User
 pub const MSTATUS_MIE: u64 = 1 << 3; // machine-mode interrupt enable.
+pub const SCAUSE_SOFT: u64 = (1 << 63) | (1); // scause for supervisor software interrupt.
+pub const SCAUSE_TIMER: u64 = (1 << 63) | (5); // scause for supervisor mode timer.
+pub const SCAUSE_ECALL_U: u64 = 8; // scause for an ecall from U-mode.
+pub const MCAUSE_SOFT: u64 = (1 << 63) | (3); // mcause for machine software interrupt.
 pub const MSTATUS_TIMER: u64 = (1 << 63) | (7); // mcause for machine mode timer.
                                                 // sstatus := Supervisor status reg.
 pub const SSTATUS_SPP: u64 = 1 << 8; // Previous mode, 1=Supervisor, 0=User
@@ -20,6 +24,9 @@ pub const MIE_MEIE: u64 = 1 << 11; // external
 pub const MIE_MTIE: u64 = 1 << 7; // timer
 pub const MIE_MSIE: u64 = 1 << 3; // software
 
+/// Machine-mode Interrupt Pending
+pub const MIP_SSIP: u64 = 1 << 1; // supervisor software, writable from M-mode
+
 /// Supervisor Interrupt Enable
 pub const SIE_SEIE: u64 = 1 << 9; // external
 pub const SIE_STIE: u64 = 1 << 5; // timer
@@ -70,6 +77,50 @@ pub fn read_scause() -> u64 {
     cause
 }
 
+/// Supervisor exception program counter, where the last S-mode trap came from.
+pub fn read_sepc() -> usize {
+    let addr: usize;
+    unsafe {
+        asm!("csrr {}, sepc", out(reg) addr);
+    }
+    addr
+}
+
+/// Where `sret` returns to.
+pub fn write_sepc(addr: usize) {
+    unsafe {
+        asm!("csrw sepc, {}", in(reg) addr);
+    }
+}
+
+/// Faulting address or instruction of the last S-mode trap.
+pub fn read_stval() -> usize {
+    let val: usize;
+    unsafe {
+        asm!("csrr {}, stval", out(reg) val);
+    }
+    val
+}
+
+/// Inside a supervisor trap handler this holds the interrupted `sp`,
+/// see `__strapvec`.
+pub fn read_sscratch() -> usize {
+    let val: usize;
+    unsafe {
+        asm!("csrr {}, sscratch", out(reg) val);
+    }
+    val
+}
+
+#[inline(always)]
+pub fn read_sp() -> usize {
+    let sp: usize;
+    unsafe {
+        asm!("mv {}, sp", out(reg) sp);
+    }
+    sp
+}
+
 /// Set mepc := machine exception program counter.
 /// (what instr (address) to go to from exception.)
 pub fn write_mepc(addr: *const ()) {
@@ -115,6 +166,39 @@ pub fn write_sip(ire: u64) {
     }
 }
 
+pub fn read_mip() -> u64 {
+    let x: u64;
+    unsafe {
+        asm!("csrr {}, mip", out(reg) x);
+    }
+    x
+}
+
+pub fn write_mip(x: u64) {
+    unsafe {
+        asm!("csrw mip, {}", in(reg) x);
+    }
+}
+
+/// Enable supervisor interrupts on this hart.
+pub fn intr_on() {
+    unsafe {
+        asm!("csrs sstatus, {}", in(reg) SSTATUS_SIE);
+    }
+}
+
+/// Disable supervisor interrupts on this hart.
+pub fn intr_off() {
+    unsafe {
+        asm!("csrc sstatus, {}", in(reg) SSTATUS_SIE);
+    }
+}
+
+/// Are supervisor interrupts enabled on this hart?
+pub fn intr_get() -> bool {
+    read_sstatus() & SSTATUS_SIE != 0
+}
+
 pub fn read_sie() -> u64 {
     let x: u64;
     unsafe {
@@ -143,6 +227,10 @@ pub fn write_mie(x: u64) {
     }
 }
 
+/// satp.ASID, WARL so not every bit need be implemented.
+pub const SATP_ASID_SHIFT: usize = 44;
+pub const SATP_ASID_MASK: usize = 0xffff;
+
 /// SATP Sv39 mode: (8L << 60)
 // From addr to satp reg: (pagetable) (SATP_SV39 | (((uint64)pagetable) >> 12))
 pub fn read_satp() -> usize {
@@ -292,3 +380,45 @@ pub fn flush_tlb() {
         asm!("sfence.vma zero, zero");
     }
 }
+
+/// Flush the TLB entries for the page holding `va`, for all ASIDs.
+pub fn flush_tlb_page(va: usize) {
+    unsafe {
+        asm!("sfence.vma {}, zero", in(reg) va);
+    }
+}
+
+/// Flush every non-global TLB entry tagged with `asid`.
+pub fn flush_tlb_asid(asid: usize) {
+    unsafe {
+        asm!("sfence.vma zero, {}", in(reg) asid);
+    }
+}
+
+/// Flush the non-global TLB entries for the page holding `va` tagged with `asid`.
+pub fn flush_tlb_page_asid(va: usize, asid: usize) {
+    unsafe {
+        asm!("sfence.vma {}, {}", in(reg) va, in(reg) asid);
+    }
+}
+
+/// Read the `time` CSR. Mirrors the CLINT mtime register, but
+/// is readable from S-mode (under OpenSBI the CLINT is M-mode only).
+pub fn read_time() -> u64 {
+    let time: u64;
+    unsafe {
+        asm!("csrr {}, time", out(reg) time);
+    }
+    time
+}
+
+/// Read the frame pointer (s0/fp). Only meaningful when the kernel
+/// is built with frame pointers.
+#[inline(always)]
+pub fn read_fp() -> usize {
+    let fp: usize;
+    unsafe {
+        asm!("mv {}, s0", out(reg) fp);
+    }
+    fp
+}
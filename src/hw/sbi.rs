@@ -0,0 +1,235 @@
+//! Supervisor Binary Interface client.
+//! Calls into the M-mode firmware (OpenSBI) when booted with the `sbi` feature.
+// Reference:
+// https://github.com/riscv-non-isa/riscv-sbi-doc/releases (v2.0)
+//
+// Calling convention: a7 = extension id, a6 = function id, a0-a5 = arguments.
+// The firmware returns an error code in a0 and a value in a1.
+use core::arch::asm;
+
+const EXT_BASE: usize = 0x10;
+const EXT_TIME: usize = 0x54494D45; // "TIME"
+const EXT_IPI: usize = 0x735049; // "sPI"
+const EXT_RFENCE: usize = 0x52464E43; // "RFNC"
+const EXT_HSM: usize = 0x48534D; // "HSM"
+const EXT_SRST: usize = 0x53525354; // "SRST"
+const EXT_DBCN: usize = 0x4442434E; // "DBCN"
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum SbiError {
+    Failed,
+    NotSupported,
+    InvalidParam,
+    Denied,
+    InvalidAddress,
+    AlreadyAvailable,
+    AlreadyStarted,
+    AlreadyStopped,
+    NoShmem,
+    Unknown(isize),
+}
+
+impl From<isize> for SbiError {
+    fn from(code: isize) -> Self {
+        match code {
+            -1 => SbiError::Failed,
+            -2 => SbiError::NotSupported,
+            -3 => SbiError::InvalidParam,
+            -4 => SbiError::Denied,
+            -5 => SbiError::InvalidAddress,
+            -6 => SbiError::AlreadyAvailable,
+            -7 => SbiError::AlreadyStarted,
+            -8 => SbiError::AlreadyStopped,
+            -9 => SbiError::NoShmem,
+            code => SbiError::Unknown(code),
+        }
+    }
+}
+
+pub type SbiResult = Result<usize, SbiError>;
+
+/// A set of harts: bit i of `mask` is hart `base + i`.
+#[derive(Copy, Clone, Debug)]
+pub struct HartMask {
+    pub mask: usize,
+    pub base: usize,
+}
+
+impl HartMask {
+    pub fn single(hart: usize) -> Self {
+        HartMask {
+            mask: 1,
+            base: hart,
+        }
+    }
+
+    /// Every hart. Base -1 means ignore the mask.
+    pub fn all() -> Self {
+        HartMask {
+            mask: 0,
+            base: usize::MAX,
+        }
+    }
+}
+
+#[inline(always)]
+fn ecall(ext: usize, fid: usize, args: [usize; 5]) -> SbiResult {
+    let (error, value): (isize, usize);
+    unsafe {
+        asm!(
+            "ecall",
+            inlateout("a0") args[0] => error,
+            inlateout("a1") args[1] => value,
+            in("a2") args[2],
+            in("a3") args[3],
+            in("a4") args[4],
+            in("a6") fid,
+            in("a7") ext,
+        );
+    }
+    match error {
+        0 => Ok(value),
+        code => Err(SbiError::from(code)),
+    }
+}
+
+// -------------------------------------------------------------------
+// Base extension
+
+pub fn spec_version() -> SbiResult {
+    ecall(EXT_BASE, 0, [0; 5])
+}
+
+pub fn impl_id() -> SbiResult {
+    ecall(EXT_BASE, 1, [0; 5])
+}
+
+pub fn impl_version() -> SbiResult {
+    ecall(EXT_BASE, 2, [0; 5])
+}
+
+/// Nonzero if the firmware implements extension `ext`.
+pub fn probe_extension(ext: usize) -> SbiResult {
+    ecall(EXT_BASE, 3, [ext, 0, 0, 0, 0])
+}
+
+// -------------------------------------------------------------------
+// TIME extension
+
+/// Program the next supervisor timer interrupt for absolute time `stime`
+/// (in `time` CSR ticks). Also clears the pending timer interrupt.
+pub fn set_timer(stime: u64) -> SbiResult {
+    ecall(EXT_TIME, 0, [stime as usize, 0, 0, 0, 0])
+}
+
+// -------------------------------------------------------------------
+// IPI extension
+
+/// Raise a supervisor software interrupt on every hart in `harts`.
+pub fn send_ipi(harts: HartMask) -> SbiResult {
+    ecall(EXT_IPI, 0, [harts.mask, harts.base, 0, 0, 0])
+}
+
+// -------------------------------------------------------------------
+// RFENCE extension
+
+pub fn remote_fence_i(harts: HartMask) -> SbiResult {
+    ecall(EXT_RFENCE, 0, [harts.mask, harts.base, 0, 0, 0])
+}
+
+/// `sfence.vma` of [start, start + size) on `harts`. A size of
+/// `usize::MAX` (or 0 and 0) flushes everything.
+pub fn remote_sfence_vma(harts: HartMask, start: usize, size: usize) -> SbiResult {
+    ecall(EXT_RFENCE, 1, [harts.mask, harts.base, start, size, 0])
+}
+
+pub fn remote_sfence_vma_asid(
+    harts: HartMask,
+    start: usize,
+    size: usize,
+    asid: usize,
+) -> SbiResult {
+    ecall(EXT_RFENCE, 2, [harts.mask, harts.base, start, size, asid])
+}
+
+// -------------------------------------------------------------------
+// Hart state management extension
+
+/// States reported by `hart_status`.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum HartState {
+    Started,
+    Stopped,
+    StartPending,
+    StopPending,
+    Suspended,
+    SuspendPending,
+    ResumePending,
+    Unknown(usize),
+}
+
+/// Start `hart` in S-mode at physical address `start` with a0 = hartid and
+/// a1 = `opaque`, paging off.
+pub fn hart_start(hart: usize, start: usize, opaque: usize) -> SbiResult {
+    ecall(EXT_HSM, 0, [hart, start, opaque, 0, 0])
+}
+
+/// Stop the calling hart. Only returns on failure.
+pub fn hart_stop() -> SbiResult {
+    ecall(EXT_HSM, 1, [0; 5])
+}
+
+pub fn hart_status(hart: usize) -> Result<HartState, SbiError> {
+    let state = ecall(EXT_HSM, 2, [hart, 0, 0, 0, 0])?;
+    Ok(match state {
+        0 => HartState::Started,
+        1 => HartState::Stopped,
+        2 => HartState::StartPending,
+        3 => HartState::StopPending,
+        4 => HartState::Suspended,
+        5 => HartState::SuspendPending,
+        6 => HartState::ResumePending,
+        other => HartState::Unknown(other),
+    })
+}
+
+/// Default retentive suspend, returns when an interrupt is pending (like `wfi`).
+pub fn hart_suspend_retentive() -> SbiResult {
+    ecall(EXT_HSM, 3, [0, 0, 0, 0, 0])
+}
+
+// -------------------------------------------------------------------
+// System reset extension
+
+#[derive(Copy, Clone, Debug)]
+pub enum ResetType {
+    Shutdown = 0,
+    ColdReboot = 1,
+    WarmReboot = 2,
+}
+
+#[derive(Copy, Clone, Debug)]
+pub enum ResetReason {
+    None = 0,
+    SystemFailure = 1,
+}
+
+/// Shut down or reboot the machine. Only returns on failure.
+pub fn system_reset(kind: ResetType, reason: ResetReason) -> SbiResult {
+    ecall(EXT_SRST, 0, [kind as usize, reason as usize, 0, 0, 0])
+}
+
+// -------------------------------------------------------------------
+// Debug console extension
+
+/// Write `bytes` to the firmware console. Returns how many were written.
+/// `bytes` must be physically addressed, so call this before paging is on
+/// or on identity mapped memory.
+pub fn console_write(bytes: &[u8]) -> SbiResult {
+    let addr = bytes.as_ptr().addr();
+    ecall(EXT_DBCN, 0, [bytes.len(), addr, 0, 0, 0])
+}
+
+pub fn console_write_byte(byte: u8) -> SbiResult {
+    ecall(EXT_DBCN, 2, [byte as usize, 0, 0, 0, 0])
+}
@@ -0,0 +1,125 @@
+//! Inter-processor interrupts.
+//!
+//! A hart pokes another by posting a message in the target's pending mask
+//! and raising a supervisor software interrupt on it. How that interrupt is
+//! raised depends on the platform:
+//! - under OpenSBI (`sbi` feature) through the SBI IPI extension,
+//! - with an ACLINT SSWI device by writing the target's SETSSIP register,
+//! - otherwise by writing the target's CLINT MSIP register, which lands in
+//!   `m_handler` as a machine software interrupt, and gets forwarded to S-mode.
+//!
+//! The receiver handles everything pending in `handle()`, called from `s_handler`.
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+#[cfg(not(feature = "sbi"))]
+use crate::device::{clint, sswi};
+use crate::hw::param::MAX_HARTS;
+use crate::hw::riscv::*;
+use crate::hw::{self, HartSet};
+use crate::lock::mutex::Mutex;
+
+/// What a hart can ask of another.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Message {
+    /// Wake up a hart waiting in `wfi`, nothing more. There is no
+    /// preemption: the hart looks at the run queue again once it is back
+    /// in its scheduler loop, see `sched::idle`.
+    Reschedule = 1 << 0,
+    /// Run the function posted by `call_on`.
+    Call = 1 << 1,
+    /// Stop for good, see `panic::halt_all`.
+    Halt = 1 << 2,
+}
+
+#[allow(clippy::declare_interior_mutable_const)]
+const NONE_PENDING: AtomicUsize = AtomicUsize::new(0);
+/// Messages waiting for each hart, a bitmask of `Message`s.
+static PENDING: [AtomicUsize; MAX_HARTS] = [NONE_PENDING; MAX_HARTS];
+
+/// Serializes `call_on`, only one remote call is in flight at a time.
+static CALL_LOCK: Mutex<()> = Mutex::new(());
+/// The function and argument of the call in flight.
+static CALL_FN: AtomicUsize = AtomicUsize::new(0);
+static CALL_ARG: AtomicUsize = AtomicUsize::new(0);
+/// Harts that have yet to run the call in flight.
+static CALL_WAITING: AtomicUsize = AtomicUsize::new(0);
+
+/// Raise a software interrupt on `hart`.
+fn raise(hart: usize) {
+    #[cfg(feature = "sbi")]
+    {
+        let _ = hw::sbi::send_ipi(hw::sbi::HartMask::single(hart));
+    }
+    #[cfg(not(feature = "sbi"))]
+    {
+        if sswi::present() {
+            sswi::send(hart);
+        } else {
+            clint::set_msip(hart);
+        }
+    }
+}
+
+/// Post `msg` to every hart in `harts`.
+pub fn send(harts: HartSet, msg: Message) {
+    for hart in harts.iter() {
+        PENDING[hart].fetch_or(msg as usize, Ordering::AcqRel);
+        raise(hart);
+    }
+}
+
+/// Run `func(arg)` on every hart in `harts` and wait until all of them have.
+/// If the caller is in `harts` it runs `func` itself, with the others.
+/// Must be called with interrupts on, or two harts calling each other would
+/// wait on each other forever.
+pub fn call_on(harts: HartSet, func: fn(usize), arg: usize) {
+    let me = hw::hartid();
+    let mut remote = harts;
+    remote.remove(me);
+
+    if !remote.is_empty() {
+        let _guard = CALL_LOCK.lock();
+        CALL_FN.store(func as usize, Ordering::Relaxed);
+        CALL_ARG.store(arg, Ordering::Relaxed);
+        CALL_WAITING.store(remote.bits(), Ordering::Release);
+        send(remote, Message::Call);
+        if harts.contains(me) {
+            func(arg);
+        }
+        while CALL_WAITING.load(Ordering::Acquire) != 0 {
+            core::hint::spin_loop();
+        }
+    } else if harts.contains(me) {
+        func(arg);
+    }
+}
+
+/// Handle a supervisor software interrupt: acknowledge it and act on
+/// everything pending for this hart.
+pub fn handle() {
+    write_sip(read_sip() & !SIE_SSIE);
+
+    let hart = hw::this_hart();
+    let pending = PENDING[hart.id()].swap(0, Ordering::AcqRel);
+
+    // Reschedule only has to get us out of `wfi`, which taking this
+    // interrupt already did.
+    if pending & Message::Call as usize != 0 {
+        let func = CALL_FN.load(Ordering::Relaxed);
+        let arg = CALL_ARG.load(Ordering::Relaxed);
+        let func: fn(usize) = unsafe { core::mem::transmute(func) };
+        func(arg);
+        CALL_WAITING.fetch_and(!(1 << hart.id()), Ordering::AcqRel);
+    }
+    if pending & Message::Halt as usize != 0 {
+        crate::panic::park();
+    }
+}
+
+/// Stop this hart for good.
+pub fn halt() -> ! {
+    intr_off();
+    loop {
+        unsafe { core::arch::asm!("wfi") };
+    }
+}
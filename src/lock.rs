@@ -1,2 +1,34 @@
 //! Kernel locks.
+//! Most of these spin. `mutex` is the simplest, `ticket` hands the lock out
+//! in arrival order, `irq_mutex` also keeps interrupts off while held,
+//! `rwlock` lets readers share, and `seqlock` never blocks readers at all.
+//! Timeouts (`lock_timeout` and friends) are in mtime ticks. `once` sets up
+//! globals exactly once, for sharing between harts.
+//!
+//! The rest sleep instead, for processes that may wait a long time: `wait`
+//! (wait queues and condition variables), `sleep_mutex` and `semaphore`.
+//!
+//! Building with the `lockdep` feature checks the order spinlocks are taken
+//! in, see `lockdep`.
+pub mod irq_mutex;
+#[cfg(feature = "lockdep")]
+pub mod lockdep;
 pub mod mutex;
+pub mod once;
+pub mod rwlock;
+pub mod semaphore;
+pub mod seqlock;
+pub mod sleep_mutex;
+pub mod ticket;
+pub mod wait;
+
+use crate::device::clint;
+
+/// mtime at which a timeout of `ticks` from now runs out.
+fn deadline(ticks: u64) -> u64 {
+    clint::read_mtime().saturating_add(ticks)
+}
+
+fn expired(deadline: u64) -> bool {
+    clint::read_mtime() >= deadline
+}
@@ -0,0 +1,86 @@
+//! Interrupt safe spinlock.
+//! A `Mutex` that also turns interrupts off on this hart while held. Use it
+//! for anything a trap handler may lock: with a plain `Mutex`, a handler
+//! that interrupts the lock holder on the same hart spins forever.
+//!
+//! Interrupt state nests per hart (`hw::push_off` / `hw::pop_off`), so
+//! holding several of these at once works, and interrupts only come back on
+//! when the last guard is dropped, and only if they were on to begin with.
+use core::mem::ManuallyDrop;
+
+use super::mutex::{Mutex, MutexGuard};
+use crate::hw;
+
+/// Returned from successfully locking an `IrqMutex`.
+pub struct IrqMutexGuard<'a, T> {
+    guard: ManuallyDrop<MutexGuard<'a, T>>,
+}
+
+impl<T> core::ops::Deref for IrqMutexGuard<'_, T> {
+    type Target = T;
+    fn deref(&self) -> &T {
+        &self.guard
+    }
+}
+
+impl<T> core::ops::DerefMut for IrqMutexGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        &mut self.guard
+    }
+}
+
+impl<T> core::ops::Drop for IrqMutexGuard<'_, T> {
+    fn drop(&mut self) {
+        // Unlock before interrupts can come back on.
+        unsafe { ManuallyDrop::drop(&mut self.guard) };
+        hw::pop_off();
+    }
+}
+
+pub struct IrqMutex<T> {
+    inner: Mutex<T>,
+}
+
+impl<T> IrqMutex<T> {
+    #[track_caller]
+    pub const fn new(value: T) -> Self {
+        IrqMutex {
+            inner: Mutex::new(value),
+        }
+    }
+
+    /// Disable interrupts, then spin for the lock.
+    #[track_caller]
+    pub fn lock(&self) -> IrqMutexGuard<T> {
+        hw::push_off();
+        IrqMutexGuard {
+            guard: ManuallyDrop::new(self.inner.lock()),
+        }
+    }
+
+    #[track_caller]
+    pub fn try_lock(&self) -> Option<IrqMutexGuard<T>> {
+        hw::push_off();
+        self.wrap(self.inner.try_lock())
+    }
+
+    /// Spin for at most `ticks` of mtime, with interrupts off.
+    #[track_caller]
+    pub fn lock_timeout(&self, ticks: u64) -> Option<IrqMutexGuard<T>> {
+        hw::push_off();
+        self.wrap(self.inner.lock_timeout(ticks))
+    }
+
+    /// Guard for a lock taken after `push_off`, or undo the `push_off`.
+    fn wrap<'a>(&'a self, guard: Option<MutexGuard<'a, T>>) -> Option<IrqMutexGuard<'a, T>> {
+        match guard {
+            Some(guard) => Some(IrqMutexGuard {
+                guard: ManuallyDrop::new(guard),
+            }),
+            None => {
+                hw::pop_off();
+                None
+            }
+        }
+    }
+}
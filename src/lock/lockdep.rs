@@ -0,0 +1,352 @@
+//! Lock dependency validator (`lockdep` feature).
+//! Catches lock ordering bugs the first time the orders are seen, rather
+//! than the one time in a thousand they actually deadlock. Locks are put in
+//! classes by where they were made: the `new` call, passed on through
+//! `#[track_caller]` by the locks built on top. So a lock a constructor
+//! makes is one class however many instances there are, like Linux'
+//! `lock_class_key`. Each hart keeps a stack of the locks it holds; taking
+//! a lock of class B while holding one of A records the edge A -> B.
+//! An acquisition that would close a cycle (B is already known to be taken
+//! before A) is reported with the chain that makes it one, before we start
+//! spinning on the lock. So are taking a lock we already hold, and a lock
+//! taken both in a trap handler and with interrupts on elsewhere: if the
+//! trap comes in while that code holds the lock, the hart spins forever.
+//! Reference: Linux Documentation/locking/lockdep-design.rst
+//!
+//! The first report turns the validator off, so the rest of the log isn't
+//! buried in follow-on reports, and so printing it can take locks.
+//!
+//! Only `Mutex` (and so `IrqMutex`) and `TicketLock` are tracked. Holding
+//! two locks of one class is fine, as long as they are different locks,
+//! but tells us nothing about their order, so adds no edge. `try_lock`
+//! can't wait, so it records the lock as held but adds no ordering.
+use core::panic::Location;
+use core::sync::atomic::*;
+
+use crate::backtrace;
+use crate::hw::param::MAX_HARTS;
+use crate::hw::riscv::intr_get;
+use crate::hw::{self, hartid};
+
+/// Most lock classes tracked at once, they are bits in a `u64`.
+pub const MAX_CLASSES: usize = 64;
+/// Most locks one hart holds at once.
+pub const MAX_HELD: usize = 16;
+
+type Site = &'static Location<'static>;
+
+static ENABLED: AtomicBool = AtomicBool::new(true);
+/// Guards `GRAPH`. Not a `Mutex`, that would come back in here.
+static GRAPH_LOCK: AtomicBool = AtomicBool::new(false);
+
+/// Class taken in a trap handler.
+const USED_IN_TRAP: usize = 0;
+/// Class taken with interrupts on, outside a trap handler.
+const USED_IRQS_ON: usize = 1;
+
+#[derive(Copy, Clone)]
+struct Class {
+    /// Where the locks of this class are made, `None` for a free slot.
+    key: Option<Site>,
+    /// Where each kind of use was first seen, see `USED_*`.
+    usage: [Option<Site>; 2],
+}
+
+impl Class {
+    const FREE: Class = Class {
+        key: None,
+        usage: [None; 2],
+    };
+}
+
+struct Graph {
+    classes: [Class; MAX_CLASSES],
+    /// `after[a]` has bit b set once b was taken while holding a.
+    after: [u64; MAX_CLASSES],
+    /// Where b was taken when the edge a -> b was first seen.
+    sites: [[Option<Site>; MAX_CLASSES]; MAX_CLASSES],
+}
+
+static mut GRAPH: Graph = Graph {
+    classes: [Class::FREE; MAX_CLASSES],
+    after: [0; MAX_CLASSES],
+    sites: [[None; MAX_CLASSES]; MAX_CLASSES],
+};
+
+#[derive(Copy, Clone)]
+struct Held {
+    /// Address of the lock.
+    lock: usize,
+    class: usize,
+    site: Site,
+}
+
+#[derive(Copy, Clone)]
+struct HeldLocks {
+    locks: [Option<Held>; MAX_HELD],
+    depth: usize,
+}
+
+impl HeldLocks {
+    const EMPTY: HeldLocks = HeldLocks {
+        locks: [None; MAX_HELD],
+        depth: 0,
+    };
+
+    fn iter(&self) -> impl Iterator<Item = &Held> {
+        self.locks[..self.depth].iter().flatten()
+    }
+}
+
+/// Locks each hart holds, in the order taken. Only touched by the hart
+/// itself, with interrupts off.
+static mut HELD: [HeldLocks; MAX_HARTS] = [HeldLocks::EMPTY; MAX_HARTS];
+
+/// Run `f` on the graph with interrupts off and everyone else kept out.
+fn with_graph<R>(f: impl FnOnce(&mut Graph, &mut HeldLocks) -> R) -> R {
+    hw::push_off();
+    while GRAPH_LOCK.swap(true, Ordering::Acquire) {
+        core::hint::spin_loop();
+    }
+    let ret = unsafe { f(&mut GRAPH, &mut HELD[hartid()]) };
+    GRAPH_LOCK.store(false, Ordering::Release);
+    hw::pop_off();
+    ret
+}
+
+/// Record that the lock at `lock`, made at `class`, is about to be taken
+/// at `site`. With `try_lock` the caller won't wait for it, and may not get
+/// it: call `acquired` once it has, instead of this.
+pub fn acquire(lock: usize, class: Site, site: Site) {
+    check(lock, class, site, false);
+}
+
+/// Record that a `try_lock` (or timed lock) got the lock at `lock`.
+pub fn acquired(lock: usize, class: Site, site: Site) {
+    check(lock, class, site, true);
+}
+
+fn check(lock: usize, key: Site, site: Site, try_lock: bool) {
+    if !ENABLED.load(Ordering::Relaxed) {
+        return;
+    }
+    // Before with_graph turns interrupts off.
+    let usage = if hw::this_hart().in_trap() {
+        Some(USED_IN_TRAP)
+    } else if intr_get() {
+        Some(USED_IRQS_ON)
+    } else {
+        None
+    };
+
+    with_graph(|graph, held| {
+        let class = match graph.class(key) {
+            Some(class) => class,
+            None => return,
+        };
+        let this = Held { lock, class, site };
+
+        if !try_lock {
+            if let Some(prev) = held.iter().find(|h| h.lock == lock) {
+                report(graph, held, "recursive locking, already holding", &this);
+                println!("  first taken at {}", prev.site);
+                return;
+            }
+        }
+
+        if let Some(usage) = usage {
+            let other = 1 - usage;
+            graph.classes[class].usage[usage].get_or_insert(site);
+            if let Some(other_site) = graph.classes[class].usage[other] {
+                report(
+                    graph,
+                    held,
+                    "lock taken both in a trap handler and with interrupts on",
+                    &this,
+                );
+                let what = ["in a trap handler", "with interrupts on"];
+                println!("  also taken {} at {}", what[other], other_site);
+                println!("  an IrqMutex keeps traps out while it is held");
+                return;
+            }
+        }
+
+        if !try_lock {
+            for prev in held.locks[..held.depth].iter().flatten() {
+                if prev.class == class || graph.after[prev.class] & (1 << class) != 0 {
+                    continue;
+                }
+                if graph.path(class, prev.class) {
+                    report(graph, held, "possible deadlock, lock order reversed", &this);
+                    println!("  known order, {} are taken before:", graph.name(class));
+                    graph.print_path(class, prev.class);
+                    return;
+                }
+                graph.after[prev.class] |= 1 << class;
+                graph.sites[prev.class][class] = Some(site);
+            }
+        }
+
+        if held.depth == MAX_HELD {
+            report(graph, held, "holding too many locks, raise MAX_HELD", &this);
+            return;
+        }
+        held.locks[held.depth] = Some(this);
+        held.depth += 1;
+    });
+}
+
+/// Record that the lock at `lock` was unlocked.
+pub fn release(lock: usize) {
+    if !ENABLED.load(Ordering::Relaxed) {
+        return;
+    }
+    with_graph(|_, held| {
+        // Locks needn't be released in the order they were taken.
+        let pos = held.locks[..held.depth]
+            .iter()
+            .rposition(|h| h.map_or(false, |h| h.lock == lock));
+        if let Some(pos) = pos {
+            held.locks.copy_within(pos + 1..held.depth, pos);
+            held.depth -= 1;
+            held.locks[held.depth] = None;
+        }
+    });
+}
+
+/// Complain if this hart holds any tracked lock, before it switches to
+/// another process that would inherit them.
+pub fn assert_none_held() {
+    if !ENABLED.load(Ordering::Relaxed) {
+        return;
+    }
+    with_graph(|graph, held| {
+        if let Some(last) = held.iter().last().copied() {
+            report(graph, held, "switching out while holding", &last);
+        }
+    });
+}
+
+impl Graph {
+    fn find(&self, key: Option<Site>) -> Option<usize> {
+        self.classes.iter().position(|c| c.key == key)
+    }
+
+    /// Class of the locks made at `key`, registering it the first time.
+    fn class(&mut self, key: Site) -> Option<usize> {
+        if let Some(class) = self.find(Some(key)) {
+            return Some(class);
+        }
+        match self.find(None) {
+            Some(class) => {
+                self.classes[class].key = Some(key);
+                Some(class)
+            }
+            None => {
+                ENABLED.store(false, Ordering::Relaxed);
+                println!("lockdep: out of lock classes, raise MAX_CLASSES. Turning off.");
+                None
+            }
+        }
+    }
+
+    /// Is `to` taken after `from`, directly or through other classes?
+    fn path(&self, from: usize, to: usize) -> bool {
+        self.walk(from, to).is_some()
+    }
+
+    /// Breadth first search of `after`, returns each class' predecessor on
+    /// the way from `from` if `to` was found.
+    fn walk(&self, from: usize, to: usize) -> Option<[usize; MAX_CLASSES]> {
+        let mut prev = [usize::MAX; MAX_CLASSES];
+        let mut seen: u64 = 1 << from;
+        let mut frontier: u64 = 1 << from;
+        while frontier != 0 {
+            let mut next = 0;
+            for a in (0..MAX_CLASSES).filter(|a| frontier & (1 << a) != 0) {
+                let new = self.after[a] & !seen;
+                for b in (0..MAX_CLASSES).filter(|b| new & (1 << b) != 0) {
+                    prev[b] = a;
+                }
+                seen |= new;
+                next |= new;
+            }
+            if seen & (1 << to) != 0 {
+                return Some(prev);
+            }
+            frontier = next;
+        }
+        None
+    }
+
+    /// Print the edges on the way from `from` to `to`, with where each was
+    /// first seen.
+    fn print_path(&self, from: usize, to: usize) {
+        let prev = match self.walk(from, to) {
+            Some(prev) => prev,
+            None => return,
+        };
+        let mut path = [0; MAX_CLASSES];
+        let mut len = 0;
+        let mut class = to;
+        while class != from {
+            path[len] = class;
+            len += 1;
+            class = prev[class];
+        }
+        let mut a = from;
+        for &b in path[..len].iter().rev() {
+            match self.sites[a][b] {
+                Some(site) => println!("    -> {}, taken at {}", self.name(b), site),
+                None => println!("    -> {}", self.name(b)),
+            }
+            a = b;
+        }
+    }
+
+    fn name(&self, class: usize) -> ClassName {
+        ClassName(self.classes[class].key)
+    }
+}
+
+/// Prints as where the locks of the class are made.
+struct ClassName(Option<Site>);
+
+impl core::fmt::Display for ClassName {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        match self.0 {
+            Some(site) => write!(f, "locks made at {}", site),
+            None => write!(f, "unknown locks"),
+        }
+    }
+}
+
+/// Prints as the lock's address, and the symbol it is in if we have them.
+struct LockName(usize);
+
+impl core::fmt::Display for LockName {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        match backtrace::symbolize(self.0) {
+            Some((name, 0)) => write!(f, "lock {:#x} ({})", self.0, name),
+            Some((name, offset)) => write!(f, "lock {:#x} ({}+{:#x})", self.0, name, offset),
+            None => write!(f, "lock {:#x}", self.0),
+        }
+    }
+}
+
+/// Turn the validator off and report taking `lock`, with what this hart
+/// holds and how it got here.
+fn report(graph: &Graph, held: &HeldLocks, what: &str, lock: &Held) {
+    ENABLED.store(false, Ordering::Relaxed);
+    println!("lockdep: hart {}: {}", hartid(), what);
+    println!("  {} at {}", LockName(lock.lock), lock.site);
+    println!("  one of the {}", graph.name(lock.class));
+    println!("  holding:");
+    for h in held.iter() {
+        println!("    {} taken at {}", LockName(h.lock), h.site);
+    }
+    if held.depth == 0 {
+        println!("    nothing");
+    }
+    backtrace::print();
+}
@@ -1,12 +1,17 @@
 //! Spinlock mutex implementation
-/// Inspiration taken in no small part from the awesome:
-/// <https://marabos.nl/atomics/building-locks.html#mutex>
-///
-/// Opportunity for improvement on interrupt safe locks.
+//! Inspiration taken in no small part from the awesome:
+//! <https://marabos.nl/atomics/building-locks.html#mutex>
+//!
+//! Opportunity for improvement on interrupt safe locks.
 use core::cell::UnsafeCell;
 use core::hint::spin_loop;
 use core::sync::atomic::*;
 
+#[cfg(feature = "lockdep")]
+use super::lockdep;
+#[cfg(feature = "lockdep")]
+use core::panic::Location;
+
 /// Returned from successfully locking a mutex.
 pub struct MutexGuard<'a, T> {
     mutex: &'a Mutex<T>,
@@ -30,6 +35,8 @@ impl<T> core::ops::DerefMut for MutexGuard<'_, T> {
 
 impl<T> core::ops::Drop for MutexGuard<'_, T> {
     fn drop(&mut self) {
+        #[cfg(feature = "lockdep")]
+        lockdep::release(self.mutex.key());
         self.mutex.lock_state.store(0, Ordering::Release);
     }
 }
@@ -40,6 +47,9 @@ impl<T> core::ops::Drop for MutexGuard<'_, T> {
 pub struct Mutex<T> {
     lock_state: AtomicU32, // (0,1) = (unlocked, locked)
     inner: UnsafeCell<T>,
+    /// Where the lock was made, its `lockdep` class.
+    #[cfg(feature = "lockdep")]
+    class: &'static Location<'static>,
 }
 
 unsafe impl<T: Send> Sync for Mutex<T> {}
@@ -47,21 +57,61 @@ unsafe impl<T: Send> Sync for Mutex<T> {}
 impl<T> Mutex<T> {
     /// Reference:
     /// <https://doc.rust-lang.org/reference/const_eval.html>
+    #[track_caller]
     pub const fn new(value: T) -> Self {
         Mutex {
             lock_state: AtomicU32::new(0),
             inner: UnsafeCell::new(value),
+            #[cfg(feature = "lockdep")]
+            class: Location::caller(),
         }
     }
 
     /// Needs to satisfy an atomic swap (acquire)
     /// then a fence so loads and stores aren't reordered until
     /// after lock is acquired.
+    #[track_caller]
     pub fn lock(&self) -> MutexGuard<T> {
+        #[cfg(feature = "lockdep")]
+        lockdep::acquire(self.key(), self.class, Location::caller());
         // Use Acquire memory order to load lock value.
         while self.lock_state.swap(1, Ordering::Acquire) == 1 {
             spin_loop();
         }
         MutexGuard { mutex: self }
     }
+
+    /// Take the lock if it is free, without spinning.
+    #[track_caller]
+    pub fn try_lock(&self) -> Option<MutexGuard<T>> {
+        match self.lock_state.swap(1, Ordering::Acquire) {
+            0 => {
+                #[cfg(feature = "lockdep")]
+                lockdep::acquired(self.key(), self.class, Location::caller());
+                Some(MutexGuard { mutex: self })
+            }
+            _ => None,
+        }
+    }
+
+    /// Spin for at most `ticks` of mtime.
+    #[track_caller]
+    pub fn lock_timeout(&self, ticks: u64) -> Option<MutexGuard<T>> {
+        let deadline = super::deadline(ticks);
+        loop {
+            if let Some(guard) = self.try_lock() {
+                return Some(guard);
+            }
+            if super::expired(deadline) {
+                return None;
+            }
+            spin_loop();
+        }
+    }
+
+    /// Identifies this lock to `lockdep`.
+    #[cfg(feature = "lockdep")]
+    fn key(&self) -> usize {
+        self as *const Self as usize
+    }
 }
@@ -0,0 +1,128 @@
+//! One time initialization.
+//! `Once<T>` holds a value that is set exactly once, by whichever hart gets
+//! there first, and can then be shared by reference from every hart. Harts
+//! that come in while another is initializing spin until it is done. This
+//! replaces `static mut` + `OnceCell`, which has no synchronization at all.
+//!
+//! `Lazy<T>` is a `Once` that knows how to initialize itself, on first use.
+//!
+//! A panic in the initializer leaves the value "initializing" forever. That
+//! is fine for the kernel, a panic stops every hart anyway.
+use core::cell::UnsafeCell;
+use core::hint::spin_loop;
+use core::mem::MaybeUninit;
+use core::sync::atomic::*;
+
+const INCOMPLETE: u8 = 0;
+const RUNNING: u8 = 1;
+const COMPLETE: u8 = 2;
+
+pub struct Once<T> {
+    state: AtomicU8, // INCOMPLETE, RUNNING or COMPLETE
+    value: UnsafeCell<MaybeUninit<T>>,
+}
+
+unsafe impl<T: Send + Sync> Sync for Once<T> {}
+unsafe impl<T: Send> Send for Once<T> {}
+
+impl<T> Once<T> {
+    pub const fn new() -> Self {
+        Once {
+            state: AtomicU8::new(INCOMPLETE),
+            value: UnsafeCell::new(MaybeUninit::uninit()),
+        }
+    }
+
+    /// Initialize with `f` unless that has already happened (or is happening
+    /// on another hart), then return the value. `f` runs at most once, and
+    /// must not come back to this `Once`, that spins forever.
+    pub fn call_once<F: FnOnce() -> T>(&self, f: F) -> &T {
+        match self
+            .state
+            .compare_exchange(INCOMPLETE, RUNNING, Ordering::Acquire, Ordering::Acquire)
+        {
+            Ok(_) => {
+                unsafe { (*self.value.get()).write(f()) };
+                self.state.store(COMPLETE, Ordering::Release);
+                unsafe { self.get_unchecked() }
+            }
+            Err(_) => self.wait(),
+        }
+    }
+
+    /// Initialize with `value`, or give it back if already initialized.
+    pub fn set(&self, value: T) -> Result<(), T> {
+        let mut value = Some(value);
+        self.call_once(|| value.take().unwrap());
+        match value {
+            None => Ok(()),
+            Some(value) => Err(value),
+        }
+    }
+
+    /// The value, if initialization has finished.
+    pub fn get(&self) -> Option<&T> {
+        match self.state.load(Ordering::Acquire) {
+            COMPLETE => Some(unsafe { self.get_unchecked() }),
+            _ => None,
+        }
+    }
+
+    /// Spin until another hart has initialized the value.
+    pub fn wait(&self) -> &T {
+        while self.state.load(Ordering::Acquire) != COMPLETE {
+            spin_loop();
+        }
+        unsafe { self.get_unchecked() }
+    }
+
+    pub fn is_completed(&self) -> bool {
+        self.state.load(Ordering::Acquire) == COMPLETE
+    }
+
+    /// Caller makes sure the state is COMPLETE.
+    unsafe fn get_unchecked(&self) -> &T {
+        (*self.value.get()).assume_init_ref()
+    }
+}
+
+impl<T> Drop for Once<T> {
+    fn drop(&mut self) {
+        if *self.state.get_mut() == COMPLETE {
+            unsafe { self.value.get_mut().assume_init_drop() };
+        }
+    }
+}
+
+/// A value initialized by `init` the first time it is dereferenced.
+pub struct Lazy<T, F = fn() -> T> {
+    once: Once<T>,
+    init: UnsafeCell<Option<F>>, // Taken by whoever runs it.
+}
+
+unsafe impl<T: Send + Sync, F: Send> Sync for Lazy<T, F> {}
+
+impl<T, F: FnOnce() -> T> Lazy<T, F> {
+    pub const fn new(init: F) -> Self {
+        Lazy {
+            once: Once::new(),
+            init: UnsafeCell::new(Some(init)),
+        }
+    }
+
+    /// Initialize now, if that hasn't happened yet.
+    pub fn force(this: &Self) -> &T {
+        this.once.call_once(|| {
+            // Only the hart that won the race to initialize gets here.
+            let init = unsafe { (*this.init.get()).take() };
+            init.expect("Lazy initializer ran twice")()
+        })
+    }
+}
+
+impl<T, F: FnOnce() -> T> core::ops::Deref for Lazy<T, F> {
+    type Target = T;
+    fn deref(&self) -> &T {
+        Lazy::force(self)
+    }
+}
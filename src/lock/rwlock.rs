@@ -0,0 +1,192 @@
+//! Reader-writer spinlock.
+//! Any number of readers, or one writer. Meant for read-mostly data such as
+//! page tables and the device registry. A waiting writer stops new readers
+//! from coming in, so a steady stream of readers can't starve it.
+use core::cell::UnsafeCell;
+use core::hint::spin_loop;
+use core::sync::atomic::*;
+
+const WRITER: u32 = 1 << 31; // Held by a writer.
+const WAITING_ONE: u32 = 1 << 16; // One writer spinning, readers hold off.
+const WAITING: u32 = WRITER - WAITING_ONE; // Waiting writer count mask.
+const READERS: u32 = WAITING_ONE - 1; // Reader count mask.
+
+/// Shared access, returned by `read`.
+pub struct RwLockReadGuard<'a, T> {
+    lock: &'a RwLock<T>,
+}
+
+/// Exclusive access, returned by `write`.
+pub struct RwLockWriteGuard<'a, T> {
+    lock: &'a RwLock<T>,
+}
+
+impl<T> core::ops::Deref for RwLockReadGuard<'_, T> {
+    type Target = T;
+    fn deref(&self) -> &T {
+        unsafe { &*self.lock.inner.get() }
+    }
+}
+
+impl<T> core::ops::Drop for RwLockReadGuard<'_, T> {
+    fn drop(&mut self) {
+        self.lock.state.fetch_sub(1, Ordering::Release);
+    }
+}
+
+impl<T> core::ops::Deref for RwLockWriteGuard<'_, T> {
+    type Target = T;
+    fn deref(&self) -> &T {
+        unsafe { &*self.lock.inner.get() }
+    }
+}
+
+impl<T> core::ops::DerefMut for RwLockWriteGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.lock.inner.get() }
+    }
+}
+
+impl<T> core::ops::Drop for RwLockWriteGuard<'_, T> {
+    fn drop(&mut self) {
+        // Leave the waiting count alone, it belongs to the next writers.
+        self.lock.state.fetch_and(!WRITER, Ordering::Release);
+    }
+}
+
+pub struct RwLock<T> {
+    state: AtomicU32, // WRITER | waiting writer count | reader count
+    inner: UnsafeCell<T>,
+}
+
+unsafe impl<T: Send + Sync> Sync for RwLock<T> {}
+
+impl<T> RwLock<T> {
+    pub const fn new(value: T) -> Self {
+        RwLock {
+            state: AtomicU32::new(0),
+            inner: UnsafeCell::new(value),
+        }
+    }
+
+    pub fn read(&self) -> RwLockReadGuard<T> {
+        loop {
+            if let Some(guard) = self.try_read() {
+                return guard;
+            }
+            spin_loop();
+        }
+    }
+
+    pub fn try_read(&self) -> Option<RwLockReadGuard<T>> {
+        let state = self.state.load(Ordering::Relaxed);
+        if state & (WRITER | WAITING) != 0 || state & READERS == READERS {
+            return None;
+        }
+        self.state
+            .compare_exchange_weak(state, state + 1, Ordering::Acquire, Ordering::Relaxed)
+            .ok()
+            .map(|_| RwLockReadGuard { lock: self })
+    }
+
+    /// Spin for at most `ticks` of mtime.
+    pub fn read_timeout(&self, ticks: u64) -> Option<RwLockReadGuard<T>> {
+        let deadline = super::deadline(ticks);
+        loop {
+            if let Some(guard) = self.try_read() {
+                return Some(guard);
+            }
+            if super::expired(deadline) {
+                return None;
+            }
+            spin_loop();
+        }
+    }
+
+    pub fn write(&self) -> RwLockWriteGuard<T> {
+        if let Some(guard) = self.try_write() {
+            return guard;
+        }
+        // Hold new readers off until we are in.
+        self.state.fetch_add(WAITING_ONE, Ordering::Relaxed);
+        loop {
+            if let Some(guard) = self.take_write(WAITING_ONE) {
+                return guard;
+            }
+            spin_loop();
+        }
+    }
+
+    /// Take the write lock if there are no readers or writer.
+    pub fn try_write(&self) -> Option<RwLockWriteGuard<T>> {
+        self.take_write(0)
+    }
+
+    /// Spin for at most `ticks` of mtime.
+    pub fn write_timeout(&self, ticks: u64) -> Option<RwLockWriteGuard<T>> {
+        if let Some(guard) = self.try_write() {
+            return Some(guard);
+        }
+        let deadline = super::deadline(ticks);
+        self.state.fetch_add(WAITING_ONE, Ordering::Relaxed);
+        loop {
+            if let Some(guard) = self.take_write(WAITING_ONE) {
+                return Some(guard);
+            }
+            if super::expired(deadline) {
+                // Stop holding readers off, unless other writers still do.
+                self.state.fetch_sub(WAITING_ONE, Ordering::Relaxed);
+                return None;
+            }
+            spin_loop();
+        }
+    }
+
+    /// Take the write lock if there are no readers or writer, and take
+    /// ourselves off the waiting count if `waiting` is `WAITING_ONE`.
+    fn take_write(&self, waiting: u32) -> Option<RwLockWriteGuard<T>> {
+        let state = self.state.load(Ordering::Relaxed);
+        if state & (WRITER | READERS) != 0 {
+            return None;
+        }
+        self.state
+            .compare_exchange(
+                state,
+                (state - waiting) | WRITER,
+                Ordering::Acquire,
+                Ordering::Relaxed,
+            )
+            .ok()
+            .map(|_| RwLockWriteGuard { lock: self })
+    }
+}
+
+/// Try and timeout paths, and that a waiting writer holds new readers off
+/// only until it gives up. Single hart, so the waiting writer is faked.
+pub fn test_rwlock() {
+    let lock = RwLock::new(0);
+    {
+        let first = lock.read();
+        let second = lock.try_read().expect("readers don't share");
+        assert!(lock.try_write().is_none(), "writer got in past readers");
+        assert!(lock.write_timeout(1_000).is_none());
+        // A timed out writer must not keep holding readers off.
+        assert!(lock.try_read().is_some(), "timed out writer blocks readers");
+        assert_eq!(*first + *second, 0);
+    }
+    lock.state.fetch_add(WAITING_ONE, Ordering::Relaxed);
+    assert!(
+        lock.try_read().is_none(),
+        "reader got past a waiting writer"
+    );
+    assert!(lock.read_timeout(1_000).is_none());
+    lock.state.fetch_sub(WAITING_ONE, Ordering::Relaxed);
+    {
+        let mut guard = lock.try_write().expect("free lock refused a writer");
+        *guard = 1;
+        assert!(lock.try_read().is_none(), "reader got in past a writer");
+        assert!(lock.try_write().is_none(), "two writers at once");
+    }
+    assert_eq!(*lock.read_timeout(1_000).expect("lock not released"), 1);
+    log!(Debug, "Successful test of reader-writer locks...");
+}
@@ -0,0 +1,39 @@
+//! Counting semaphore.
+//! `down` takes a unit, sleeping on a `WaitQueue` while there are none,
+//! `up` gives one back. `up` is fine from trap handlers.
+use core::sync::atomic::*;
+
+use super::wait::WaitQueue;
+
+pub struct Semaphore {
+    count: AtomicUsize,
+    queue: WaitQueue,
+}
+
+impl Semaphore {
+    #[track_caller]
+    pub const fn new(count: usize) -> Self {
+        Semaphore {
+            count: AtomicUsize::new(count),
+            queue: WaitQueue::new(),
+        }
+    }
+
+    pub fn down(&self) {
+        self.queue.wait_until(|| self.try_down());
+    }
+
+    /// Take a unit if there is one, without sleeping.
+    pub fn try_down(&self) -> bool {
+        self.count
+            .fetch_update(Ordering::Acquire, Ordering::Relaxed, |count| {
+                count.checked_sub(1)
+            })
+            .is_ok()
+    }
+
+    pub fn up(&self) {
+        self.count.fetch_add(1, Ordering::Release);
+        self.queue.wake_one();
+    }
+}
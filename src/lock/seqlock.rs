@@ -0,0 +1,117 @@
+//! Sequence lock.
+//! Readers never block: they copy the data out and retry if a writer got in
+//! the way, which the sequence number tells them. It is odd while a write
+//! is in progress and changes with every write. Writers exclude each other
+//! with a spinning flag. Good for small, often read, rarely written `Copy`
+//! data, such as the time of day.
+use core::cell::UnsafeCell;
+use core::hint::spin_loop;
+use core::sync::atomic::*;
+
+pub struct SeqLock<T: Copy> {
+    seq: AtomicUsize,
+    writing: AtomicBool,
+    inner: UnsafeCell<T>,
+}
+
+unsafe impl<T: Copy + Send> Sync for SeqLock<T> {}
+
+impl<T: Copy> SeqLock<T> {
+    pub const fn new(value: T) -> Self {
+        SeqLock {
+            seq: AtomicUsize::new(0),
+            writing: AtomicBool::new(false),
+            inner: UnsafeCell::new(value),
+        }
+    }
+
+    /// A consistent copy of the data.
+    pub fn read(&self) -> T {
+        loop {
+            if let Some(value) = self.try_read() {
+                return value;
+            }
+            spin_loop();
+        }
+    }
+
+    /// A copy of the data, or None if a writer got in the way.
+    pub fn try_read(&self) -> Option<T> {
+        let before = self.seq.load(Ordering::Acquire);
+        if before & 1 != 0 {
+            return None;
+        }
+        // May race with a writer, in which case we throw the copy away.
+        let value = unsafe { self.inner.get().read_volatile() };
+        fence(Ordering::Acquire);
+        match self.seq.load(Ordering::Relaxed) == before {
+            true => Some(value),
+            false => None,
+        }
+    }
+
+    /// Like `read`, but give up after `ticks` of mtime.
+    pub fn read_timeout(&self, ticks: u64) -> Option<T> {
+        let deadline = super::deadline(ticks);
+        loop {
+            if let Some(value) = self.try_read() {
+                return Some(value);
+            }
+            if super::expired(deadline) {
+                return None;
+            }
+            spin_loop();
+        }
+    }
+
+    /// Replace the data.
+    pub fn write(&self, value: T) {
+        self.update(|data| *data = value);
+    }
+
+    /// Change the data in place with `f`.
+    pub fn update<F: FnOnce(&mut T)>(&self, f: F) {
+        while self.writing.swap(true, Ordering::Acquire) {
+            spin_loop();
+        }
+        self.update_locked(f);
+    }
+
+    /// Like `update`, but only if no other writer is at it.
+    pub fn try_update<F: FnOnce(&mut T)>(&self, f: F) -> bool {
+        if self.writing.swap(true, Ordering::Acquire) {
+            return false;
+        }
+        self.update_locked(f);
+        true
+    }
+
+    /// Bump the sequence around `f`, then let the next writer in.
+    fn update_locked<F: FnOnce(&mut T)>(&self, f: F) {
+        self.seq.fetch_add(1, Ordering::Relaxed);
+        fence(Ordering::Release);
+        unsafe { f(&mut *self.inner.get()) };
+        self.seq.fetch_add(1, Ordering::Release);
+        self.writing.store(false, Ordering::Release);
+    }
+}
+
+/// Reads see every write, and not a write in progress.
+pub fn test_seqlock() {
+    let lock = SeqLock::new((0u64, 0u64));
+    lock.write((1, 1));
+    assert_eq!(lock.read(), (1, 1));
+    lock.update(|pair| {
+        pair.0 = 2;
+        pair.1 = 2;
+    });
+    assert_eq!(lock.read_timeout(1_000), Some((2, 2)));
+    // Fake a writer half way through.
+    lock.seq.fetch_add(1, Ordering::Relaxed);
+    assert!(lock.try_read().is_none(), "read during a write");
+    assert!(lock.read_timeout(1_000).is_none());
+    lock.seq.fetch_add(1, Ordering::Relaxed);
+    assert!(lock.try_update(|pair| pair.0 = 3));
+    assert_eq!(lock.try_read(), Some((3, 2)));
+    log!(Debug, "Successful test of sequence locks...");
+}
@@ -0,0 +1,77 @@
+//! Sleeping mutex.
+//! Like `mutex::Mutex`, but a process that finds it locked sleeps on a
+//! `WaitQueue` instead of spinning, so the hart can run something else.
+//! Use it for locks held across long waits (disk I/O, console input), never
+//! from trap handlers.
+use core::cell::UnsafeCell;
+use core::sync::atomic::*;
+
+use super::wait::WaitQueue;
+
+/// Returned from successfully locking a sleeping mutex.
+pub struct SleepMutexGuard<'a, T> {
+    mutex: &'a SleepMutex<T>,
+}
+
+impl<'a, T> SleepMutexGuard<'a, T> {
+    /// The mutex this guard locks, for `Condvar`.
+    pub(super) fn mutex(&self) -> &'a SleepMutex<T> {
+        self.mutex
+    }
+}
+
+impl<T> core::ops::Deref for SleepMutexGuard<'_, T> {
+    type Target = T;
+    fn deref(&self) -> &T {
+        unsafe { &*self.mutex.inner.get() }
+    }
+}
+
+impl<T> core::ops::DerefMut for SleepMutexGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.mutex.inner.get() }
+    }
+}
+
+impl<T> core::ops::Drop for SleepMutexGuard<'_, T> {
+    fn drop(&mut self) {
+        self.mutex.locked.store(false, Ordering::Release);
+        self.mutex.queue.wake_one();
+    }
+}
+
+pub struct SleepMutex<T> {
+    locked: AtomicBool,
+    queue: WaitQueue,
+    inner: UnsafeCell<T>,
+}
+
+unsafe impl<T: Send> Sync for SleepMutex<T> {}
+
+impl<T> SleepMutex<T> {
+    #[track_caller]
+    pub const fn new(value: T) -> Self {
+        SleepMutex {
+            locked: AtomicBool::new(false),
+            queue: WaitQueue::new(),
+            inner: UnsafeCell::new(value),
+        }
+    }
+
+    pub fn lock(&self) -> SleepMutexGuard<T> {
+        loop {
+            if let Some(guard) = self.try_lock() {
+                return guard;
+            }
+            self.queue
+                .wait_until(|| !self.locked.load(Ordering::Relaxed));
+        }
+    }
+
+    pub fn try_lock(&self) -> Option<SleepMutexGuard<T>> {
+        match self.locked.swap(true, Ordering::Acquire) {
+            false => Some(SleepMutexGuard { mutex: self }),
+            true => None,
+        }
+    }
+}
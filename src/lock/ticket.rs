@@ -0,0 +1,114 @@
+//! Fair spinlock.
+//! Each locker takes a ticket and waits for it to be served, so the lock
+//! goes to harts in the order they asked for it. A swap based `Mutex` can
+//! let one hart win over and over while another starves.
+use core::cell::UnsafeCell;
+use core::hint::spin_loop;
+use core::sync::atomic::*;
+
+#[cfg(feature = "lockdep")]
+use super::lockdep;
+#[cfg(feature = "lockdep")]
+use core::panic::Location;
+
+/// Returned from successfully locking a ticket lock.
+pub struct TicketLockGuard<'a, T> {
+    lock: &'a TicketLock<T>,
+}
+
+impl<T> core::ops::Deref for TicketLockGuard<'_, T> {
+    type Target = T;
+    fn deref(&self) -> &T {
+        unsafe { &*self.lock.inner.get() }
+    }
+}
+
+impl<T> core::ops::DerefMut for TicketLockGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.lock.inner.get() }
+    }
+}
+
+impl<T> core::ops::Drop for TicketLockGuard<'_, T> {
+    fn drop(&mut self) {
+        #[cfg(feature = "lockdep")]
+        lockdep::release(self.lock.key());
+        self.lock.serving.fetch_add(1, Ordering::Release);
+    }
+}
+
+pub struct TicketLock<T> {
+    next: AtomicU32,    // Next ticket to hand out.
+    serving: AtomicU32, // Ticket that holds the lock.
+    inner: UnsafeCell<T>,
+    /// Where the lock was made, its `lockdep` class.
+    #[cfg(feature = "lockdep")]
+    class: &'static Location<'static>,
+}
+
+unsafe impl<T: Send> Sync for TicketLock<T> {}
+
+impl<T> TicketLock<T> {
+    #[track_caller]
+    pub const fn new(value: T) -> Self {
+        TicketLock {
+            next: AtomicU32::new(0),
+            serving: AtomicU32::new(0),
+            inner: UnsafeCell::new(value),
+            #[cfg(feature = "lockdep")]
+            class: Location::caller(),
+        }
+    }
+
+    #[track_caller]
+    pub fn lock(&self) -> TicketLockGuard<T> {
+        #[cfg(feature = "lockdep")]
+        lockdep::acquire(self.key(), self.class, Location::caller());
+        let ticket = self.next.fetch_add(1, Ordering::Relaxed);
+        while self.serving.load(Ordering::Acquire) != ticket {
+            spin_loop();
+        }
+        TicketLockGuard { lock: self }
+    }
+
+    /// Take the lock only if nobody holds or waits for it.
+    #[track_caller]
+    pub fn try_lock(&self) -> Option<TicketLockGuard<T>> {
+        let serving = self.serving.load(Ordering::Acquire);
+        self.next
+            .compare_exchange(
+                serving,
+                serving.wrapping_add(1),
+                Ordering::Acquire,
+                Ordering::Relaxed,
+            )
+            .ok()
+            .map(|_| {
+                #[cfg(feature = "lockdep")]
+                lockdep::acquired(self.key(), self.class, Location::caller());
+                TicketLockGuard { lock: self }
+            })
+    }
+
+    /// Spin for at most `ticks` of mtime. A ticket can't be given back, so
+    /// this retries `try_lock` instead of queueing, and isn't fair.
+    #[track_caller]
+    pub fn lock_timeout(&self, ticks: u64) -> Option<TicketLockGuard<T>> {
+        let deadline = super::deadline(ticks);
+        loop {
+            if let Some(guard) = self.try_lock() {
+                return Some(guard);
+            }
+            if super::expired(deadline) {
+                return None;
+            }
+            spin_loop();
+        }
+    }
+
+    /// Identifies this lock to `lockdep`.
+    #[cfg(feature = "lockdep")]
+    fn key(&self) -> usize {
+        self as *const Self as usize
+    }
+}
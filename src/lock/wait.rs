@@ -0,0 +1,144 @@
+//! Wait queues and condition variables.
+//! A `WaitQueue` is where processes sleep until something they wait for
+//! happens. Waiters re-check their condition after every wakeup, so wakeups
+//! may be spurious but are never lost: the queue counts wakeups in `seq`,
+//! and a waiter only goes to sleep if nothing woke the queue since it last
+//! checked its condition.
+//!
+//! Callers that aren't a process (boot code on a hart with nothing to
+//! switch to) can wait too, they spin on `seq` instead of sleeping.
+//! Waking is fine from trap handlers and other harts. Waiting in a trap
+//! handler panics: it would switch away from the hart's trap stack, or spin
+//! with interrupts off.
+use alloc::boxed::Box;
+use alloc::collections::VecDeque;
+use core::hint::spin_loop;
+use core::sync::atomic::*;
+
+use super::irq_mutex::IrqMutex;
+use super::sleep_mutex::SleepMutexGuard;
+use crate::hw;
+use crate::sched;
+use crate::vm::process::{Process, ProcessState};
+
+pub struct WaitQueue {
+    seq: AtomicUsize, // Bumped by every wakeup.
+    waiters: IrqMutex<VecDeque<Box<Process>>>,
+}
+
+impl WaitQueue {
+    #[track_caller]
+    pub const fn new() -> Self {
+        WaitQueue {
+            seq: AtomicUsize::new(0),
+            waiters: IrqMutex::new(VecDeque::new()),
+        }
+    }
+
+    /// Return once `cond()` holds, sleeping in between checks.
+    pub fn wait_until<F: FnMut() -> bool>(&self, mut cond: F) {
+        loop {
+            let seq = self.seq.load(Ordering::Acquire);
+            if cond() {
+                return;
+            }
+            self.sleep(seq);
+        }
+    }
+
+    /// Sleep unless the queue was woken since `seq` (from `seq()`).
+    /// May return early, callers re-check what they are waiting for.
+    #[track_caller]
+    pub fn sleep(&self, seq: usize) {
+        assert!(!hw::this_hart().in_trap(), "waiting in a trap handler");
+        if sched::in_process() {
+            sched::block_on(self, seq);
+        } else {
+            while self.seq.load(Ordering::Acquire) == seq {
+                spin_loop();
+            }
+        }
+    }
+
+    /// Wakeup count to pass to `sleep`. Read it before checking the
+    /// condition being waited on.
+    pub fn seq(&self) -> usize {
+        self.seq.load(Ordering::Acquire)
+    }
+
+    /// Called by the scheduler once `process` has switched out to wait here.
+    pub(crate) fn park(&self, mut process: Box<Process>, seq: usize) {
+        let mut waiters = self.waiters.lock();
+        // A waker bumps seq before it looks at the waiters, so either it
+        // finds the process queued or we see the bump here.
+        if self.seq.load(Ordering::Acquire) == seq {
+            process.set_state(ProcessState::Wait);
+            waiters.push_back(process);
+        } else {
+            drop(waiters);
+            sched::make_ready(process);
+        }
+    }
+
+    /// Wake the longest waiting process, and any non-process waiters.
+    pub fn wake_one(&self) {
+        self.seq.fetch_add(1, Ordering::AcqRel);
+        let waiter = self.waiters.lock().pop_front();
+        if let Some(process) = waiter {
+            sched::make_ready(process);
+        }
+    }
+
+    /// Wake everyone.
+    pub fn wake_all(&self) {
+        self.seq.fetch_add(1, Ordering::AcqRel);
+        let waiters = core::mem::take(&mut *self.waiters.lock());
+        for process in waiters {
+            sched::make_ready(process);
+        }
+    }
+}
+
+/// Condition variable for use with a `SleepMutex`.
+pub struct Condvar {
+    queue: WaitQueue,
+}
+
+impl Condvar {
+    #[track_caller]
+    pub const fn new() -> Self {
+        Condvar {
+            queue: WaitQueue::new(),
+        }
+    }
+
+    /// Unlock `guard`, sleep until notified, then lock again.
+    /// Wakeups may be spurious, check the condition in a loop.
+    pub fn wait<'a, T>(&self, guard: SleepMutexGuard<'a, T>) -> SleepMutexGuard<'a, T> {
+        let mutex = guard.mutex();
+        let seq = self.queue.seq();
+        drop(guard);
+        self.queue.sleep(seq);
+        mutex.lock()
+    }
+
+    /// Like `wait`, until `cond` holds for the protected data.
+    pub fn wait_while<'a, T, F: FnMut(&mut T) -> bool>(
+        &self,
+        mut guard: SleepMutexGuard<'a, T>,
+        mut cond: F,
+    ) -> SleepMutexGuard<'a, T> {
+        while cond(&mut guard) {
+            guard = self.wait(guard);
+        }
+        guard
+    }
+
+    pub fn notify_one(&self) {
+        self.queue.wake_one();
+    }
+
+    pub fn notify_all(&self) {
+        self.queue.wake_all();
+    }
+}
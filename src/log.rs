@@ -1,4 +1,26 @@
 //! Logging and printing macros
+//!
+//! `log!` records go to the console and into an in-memory ring (think
+//! `dmesg`) stamped with the mtime they were made at and the hart that made
+//! them. Each record is formatted in full before anything is written, under
+//! one lock, so lines from different harts no longer interleave. Records
+//! below the minimum level (`set_level`) are dropped.
+//!
+//! The minimum level can be overridden per module (and its submodules) with
+//! `set_filter`, or at boot from the kernel command line (`/chosen/bootargs`,
+//! `APPEND=... cargo run`) or the `KLOG` environment variable at build time:
+//! ```text
+//! log=warn log.vm=info log.vm::ptable=debug
+//! ```
+//! Module paths are relative to the crate, `main` being the crate root.
+//! Building with the `no-debug-log` feature compiles `log!(Debug, ...)` out.
+use core::fmt::{self, Write};
+use core::sync::atomic::{AtomicU8, Ordering};
+
+use crate::hw;
+use crate::hw::fdt;
+use crate::lock::irq_mutex::IrqMutex;
+use crate::time;
 
 macro_rules! print
 {
@@ -23,6 +45,7 @@ macro_rules! println
     });
 }
 
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
 pub enum LogSeverity {
     Debug,
     Info,
@@ -30,6 +53,36 @@ pub enum LogSeverity {
     Error,
 }
 
+impl LogSeverity {
+    fn tag(&self) -> &'static str {
+        match self {
+            LogSeverity::Debug => "[DEBUG]",
+            LogSeverity::Info => "[INFO]",
+            LogSeverity::Warning => "[WARN]",
+            LogSeverity::Error => "[ERROR]",
+        }
+    }
+
+    pub fn parse(name: &str) -> Option<Self> {
+        match name {
+            "debug" => Some(LogSeverity::Debug),
+            "info" => Some(LogSeverity::Info),
+            "warn" | "warning" => Some(LogSeverity::Warning),
+            "error" => Some(LogSeverity::Error),
+            _ => None,
+        }
+    }
+
+    fn from_u8(level: u8) -> Self {
+        match level {
+            0 => LogSeverity::Debug,
+            1 => LogSeverity::Info,
+            2 => LogSeverity::Warning,
+            _ => LogSeverity::Error,
+        }
+    }
+}
+
 // use as `log::log!(Warning, "This is a test of the warning logging!");`
 // in a while that has
 // ```
@@ -40,31 +93,253 @@ pub enum LogSeverity {
 
 macro_rules! log
 {
-    (Debug, $fmt:expr) => ({
-        print!(concat!("[DEBUG] ", $fmt, "\r\n"))
-    });
-    (Info, $fmt:expr) => ({
-        print!(concat!("[INFO] ", $fmt, "\r\n"))
-    });
-    (Warning, $fmt:expr) => ({
-        print!(concat!("[WARN] ", $fmt, "\r\n"))
+    (Debug, $($args:tt)+) => ({
+        // Still type checked, but gone from the binary with `no-debug-log`.
+        if !cfg!(feature = "no-debug-log") {
+            $crate::log::log!(@record Debug, $($args)+)
+        }
     });
-    (Error, $fmt:expr) => ({
-        print!(concat!("[ERROR] ", $fmt, "\r\n"))
+    ($level:ident, $($args:tt)+) => ({
+        $crate::log::log!(@record $level, $($args)+)
     });
 
-    (Debug, $fmt:expr, $($args:tt)+) => ({
-        print!(concat!("[DEBUG] ", $fmt, "\r\n"), $($args)+)
+    (@record $level:ident, $fmt:expr) => ({
+        $crate::log::record($crate::log::LogSeverity::$level, module_path!(), format_args!($fmt))
     });
-    (Info, $fmt:expr, $($args:tt)+) => ({
-        print!(concat!("[INFO] ", $fmt, "\r\n"), $($args)+)
-    });
-    (Warning, $fmt:expr, $($args:tt)+) => ({
-        print!(concat!("[WARN] ", $fmt, "\r\n"), $($args)+)
-    });
-    (Error, $fmt:expr, $($args:tt)+) => ({
-        print!(concat!("[ERROR] ", $fmt, "\r\n"), $($args)+)
+    (@record $level:ident, $fmt:expr, $($args:tt)+) => ({
+        $crate::log::record($crate::log::LogSeverity::$level, module_path!(), format_args!($fmt, $($args)+))
     });
 }
 
 pub(crate) use log;
+
+/// Bytes of log history kept.
+pub const LOG_BUF_SIZE: usize = 16 * 1024;
+/// Longest record, longer ones are cut short.
+const LINE_MAX: usize = 256;
+
+/// Most per module filters.
+pub const MAX_FILTERS: usize = 16;
+
+/// Records below this level are dropped, unless a filter says otherwise.
+static MIN_LEVEL: AtomicU8 = AtomicU8::new(LogSeverity::Debug as u8);
+
+/// Minimum level for `module` and everything under it.
+#[derive(Copy, Clone)]
+struct Filter {
+    module: &'static str,
+    level: LogSeverity,
+}
+
+static FILTERS: IrqMutex<[Option<Filter>; MAX_FILTERS]> = IrqMutex::new([None; MAX_FILTERS]);
+
+static LOG: IrqMutex<LogRing> = IrqMutex::new(LogRing {
+    buf: [0; LOG_BUF_SIZE],
+    head: 0,
+});
+
+/// Circular buffer of formatted records, oldest overwritten first.
+struct LogRing {
+    buf: [u8; LOG_BUF_SIZE],
+    /// Total bytes ever written, the next write goes at `head % LOG_BUF_SIZE`.
+    head: usize,
+}
+
+impl LogRing {
+    fn push(&mut self, bytes: &[u8]) {
+        for &b in bytes {
+            self.buf[self.head % LOG_BUF_SIZE] = b;
+            self.head += 1;
+        }
+    }
+
+    fn byte(&self, pos: usize) -> u8 {
+        self.buf[pos % LOG_BUF_SIZE]
+    }
+
+    /// Position of the first whole line among the last `len` bytes.
+    fn start_of_last(&self, len: usize) -> usize {
+        let oldest = self.head.saturating_sub(LOG_BUF_SIZE);
+        let mut start = self.head - len.min(self.head - oldest);
+        if start == 0 || (start > oldest && self.byte(start - 1) == b'\n') {
+            return start;
+        }
+        // Skip the partial line we'd start in the middle of.
+        while start < self.head && self.byte(start) != b'\n' {
+            start += 1;
+        }
+        (start + 1).min(self.head)
+    }
+}
+
+/// Fixed size line we format records into, truncating.
+struct Line {
+    buf: [u8; LINE_MAX],
+    len: usize,
+}
+
+impl Line {
+    const fn new() -> Self {
+        Line {
+            buf: [0; LINE_MAX],
+            len: 0,
+        }
+    }
+
+    fn as_str(&self) -> &str {
+        core::str::from_utf8(&self.buf[..self.len]).unwrap_or("<bad utf8>")
+    }
+}
+
+impl Write for Line {
+    /// Keeps room for the "\r\n" that ends every record, and
+    /// only cuts `s` on a character boundary.
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let mut n = s.len().min(LINE_MAX - 2 - self.len);
+        while !s.is_char_boundary(n) {
+            n -= 1;
+        }
+        self.buf[self.len..self.len + n].copy_from_slice(&s.as_bytes()[..n]);
+        self.len += n;
+        Ok(())
+    }
+}
+
+/// Set the minimum level of records that are kept.
+pub fn set_level(level: LogSeverity) {
+    MIN_LEVEL.store(level as u8, Ordering::Relaxed);
+}
+
+pub fn level() -> LogSeverity {
+    LogSeverity::from_u8(MIN_LEVEL.load(Ordering::Relaxed))
+}
+
+/// Set the minimum level for `module` (say `vm::ptable`) and its submodules,
+/// replacing any earlier filter for the same module.
+pub fn set_filter(module: &'static str, level: LogSeverity) {
+    let mut filters = FILTERS.lock();
+    let slot = filters
+        .iter()
+        .position(|f| f.map_or(false, |f| f.module == module))
+        .or_else(|| filters.iter().position(|f| f.is_none()));
+    match slot {
+        Some(i) => filters[i] = Some(Filter { module, level }),
+        None => println!("[WARN] Too many log filters, ignoring {}", module),
+    }
+}
+
+/// Minimum level for records from `module_path`, as given by `module_path!()`.
+fn level_for(module_path: &str) -> LogSeverity {
+    // Drop the crate name, what's left is what filters are written against.
+    let path = match module_path.split_once("::") {
+        Some((_, path)) => path,
+        None => "main",
+    };
+    let covers = |module: &str| {
+        path.strip_prefix(module)
+            .map_or(false, |rest| rest.is_empty() || rest.starts_with("::"))
+    };
+    FILTERS
+        .lock()
+        .iter()
+        .flatten()
+        .filter(|f| covers(f.module))
+        .max_by_key(|f| f.module.len())
+        .map_or_else(level, |f| f.level)
+}
+
+/// Apply whitespace separated `log=<level>` and `log.<module>=<level>` options.
+fn parse_options(options: &'static str) {
+    for option in options.split_whitespace() {
+        let (key, level) = match option.split_once('=') {
+            Some((key, value)) => match LogSeverity::parse(value) {
+                Some(level) => (key, level),
+                None => continue,
+            },
+            None => continue,
+        };
+        if key == "log" {
+            set_level(level);
+        } else if let Some(module) = key.strip_prefix("log.") {
+            set_filter(module, level);
+        }
+    }
+}
+
+/// Set up levels and filters from `KLOG` at build time, then from the kernel
+/// command line, which wins. Call once on hart 0.
+pub fn init() {
+    if let Some(options) = option_env!("KLOG") {
+        parse_options(options);
+    }
+    let bootargs = fdt::boot_fdt()
+        .and_then(|fdt| fdt.find_node("/chosen"))
+        .and_then(|chosen| chosen.property("bootargs"))
+        .and_then(|p| p.as_str());
+    if let Some(options) = bootargs {
+        parse_options(options);
+    }
+}
+
+/// Back end of `log!`.
+pub fn record(level: LogSeverity, module_path: &str, args: fmt::Arguments) {
+    if level < level_for(module_path) {
+        return;
+    }
+    let ns = time::monotonic_ns();
+    let secs = ns / time::NS_PER_SEC;
+    let micros = ns % time::NS_PER_SEC / 1_000;
+
+    let mut line = Line::new();
+    let _ = write!(
+        line,
+        "[{:5}.{:06}] hart {} {} {}",
+        secs,
+        micros,
+        hw::hartid(),
+        level.tag(),
+        args
+    );
+    line.buf[line.len..line.len + 2].copy_from_slice(b"\r\n");
+    line.len += 2;
+
+    let mut ring = LOG.lock();
+    ring.push(&line.buf[..line.len]);
+    print!("{}", line.as_str());
+}
+
+/// Copy as much recent log history as fits into `out`, oldest first and
+/// starting on a line boundary. Returns the bytes written.
+pub fn read(out: &mut [u8]) -> usize {
+    let ring = LOG.lock();
+    let start = ring.start_of_last(out.len());
+    let n = ring.head - start;
+    for (i, byte) in out[..n].iter_mut().enumerate() {
+        *byte = ring.byte(start + i);
+    }
+    n
+}
+
+/// Print the whole log history.
+pub fn dump() {
+    let ring = LOG.lock();
+    let mut line = Line::new();
+    for pos in ring.start_of_last(LOG_BUF_SIZE)..ring.head {
+        let byte = ring.byte(pos);
+        line.buf[line.len] = byte;
+        line.len += 1;
+        if byte == b'\n' || line.len == LINE_MAX {
+            // A full chunk may end in the middle of a character, carry the
+            // start of it over to the next one.
+            let keep = match core::str::from_utf8(&line.buf[..line.len]) {
+                Err(e) if e.error_len().is_none() => e.valid_up_to(),
+                _ => line.len,
+            };
+            let rest = line.len;
+            line.len = keep;
+            print!("{}", line.as_str());
+            line.buf.copy_within(keep..rest, 0);
+            line.len = rest - keep;
+        }
+    }
+}
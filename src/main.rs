@@ -10,14 +10,24 @@
 #![feature(unsized_fn_params)]
 #![allow(dead_code)]
 use core::panic::PanicInfo;
+use core::sync::atomic::{AtomicBool, Ordering};
 extern crate alloc;
 
 #[macro_use]
 pub mod log;
+#[macro_use]
+pub mod percpu;
 pub mod asm;
+pub mod backtrace;
 pub mod device;
 pub mod hw;
+pub mod ipi;
 pub mod lock;
+pub mod panic;
+pub mod sched;
+pub mod syscall;
+pub mod time;
+pub mod timer;
 pub mod trap;
 pub mod vm;
 
@@ -41,14 +51,17 @@ fn panic(info: &PanicInfo) -> ! {
             println!("PANIC! {} at {}:{}", msg, loc.file(), loc.line());
         }
     }
-    loop {}
+    backtrace::print();
+    crate::panic::halt_all()
 }
 
 /// This gets called from entry.S and runs on each hart.
 /// Run configuration steps that will allow us to run the
-/// kernel in supervisor mode.
+/// kernel in supervisor mode. `dtb` is the physical address of the
+/// flattened device tree, passed along from the previous boot stage.
+#[cfg(not(feature = "sbi"))]
 #[no_mangle]
-pub extern "C" fn _start() {
+pub extern "C" fn _start(_hartid: usize, dtb: usize) {
     // xv6-riscv/kernel/start.c
     let fn_main = main as *const ();
 
@@ -81,6 +94,9 @@ pub extern "C" fn _start() {
     write_pmpaddr0(0x3fffffffffffff_u64); // RTFM
     write_pmpcfg0(0xf); // 1st 8 bits are pmp0cfg
 
+    // Every hart gets the same blob, so whoever gets here last is fine.
+    hw::fdt::set_boot_fdt(dtb);
+
     // Store each hart's hartid in its tp reg for identification.
     let hartid = read_mhartid();
     write_tp(hartid);
@@ -92,18 +108,57 @@ pub extern "C" fn _start() {
     call_mret();
 }
 
+/// This gets called from entry.S and runs on each hart when booted by
+/// OpenSBI. We are already in supervisor mode with the firmware handling
+/// everything M-mode, so there is much less to do.
+#[cfg(feature = "sbi")]
+#[no_mangle]
+pub extern "C" fn _start(hartid: usize, dtb: usize) -> ! {
+    hw::fdt::set_boot_fdt(dtb);
+
+    // Store each hart's hartid in its tp reg for identification.
+    write_tp(hartid as u64);
+
+    // Disable paging while setting up.
+    write_satp(0);
+
+    //Supervisor interrupt enable.
+    let sie = read_sie() | SIE_SEIE | SIE_STIE | SIE_SSIE;
+    write_sie(sie);
+
+    // Bring up the harts the firmware left stopped.
+    hw::start_harts(hartid, dtb);
+
+    hw::timerinit();
+    main()
+}
+
+/// Set by hart 0 once global initialization is done. Other harts wait on
+/// this before touching anything hart 0 sets up (page table, heap, devices).
+static KERNEL_READY: AtomicBool = AtomicBool::new(false);
+
 // Primary kernel bootstrap function.
 // We ensure that we only initialize kernel subsystems
 // one time by only doing so on hart0.
 fn main() -> ! {
+    // `_start` left our hartid in tp, swap it for our Hart record.
+    let id = read_tp() as usize;
+    hw::hart_init(id);
+
     // We only bootstrap on hart0.
-    let id = read_tp();
     if id == 0 {
         uart::Uart::init();
+        log::init();
         println!("{}", param::BANNER);
         log!(Info, "Bootstrapping on hart0...");
         trap::init();
         log!(Info, "Finished trap init...");
+        device::probe();
+        log!(Info, "Finished device discovery...");
+        time::init();
+        log!(Debug, "Testing reader-writer and sequence locks...");
+        lock::rwlock::test_rwlock();
+        lock::seqlock::test_seqlock();
         let _ = vm::init();
         log!(Info, "Initialized the kernel page table...");
         unsafe {
@@ -115,11 +170,35 @@ fn main() -> ! {
         log!(Debug, "Testing phys page extent allocation and freeing...");
         vm::test_phys_page();
         log!(Debug, "Successful phys page extent allocation and freeing...");
+        #[cfg(feature = "heap-debug")]
+        vm::heap_report();
+        log!(Info, "Memory usage:\r\n{}", vm::stats());
+        sched::workqueue::init();
+        log!(Debug, "Testing kernel threads and workqueue...");
+        sched::kthread::test_kthread();
+        time::test_time();
+        log!(Debug, "Testing idle accounting...");
+        sched::idle::test_idle();
+        log!(Debug, "Testing one-shot and periodic timers...");
+        timer::test_timer();
         log!(Info, "Completed all hart0 initialization and testing...");
+        KERNEL_READY.store(true, Ordering::Release);
+        // Wake the other harts from their `wfi` below.
+        ipi::send(hw::HartSet::others(), ipi::Message::Reschedule);
+        intr_on();
     } else {
-        //Interrupt other harts to init kpgtable.
+        // Wait for hart0, then set up our own trap vector and paging.
+        // Timer interrupts were already enabled per hart in `_start`.
+        // Interrupts are off, so `wfi` only waits, for hart0's IPI.
+        while !KERNEL_READY.load(Ordering::Acquire) {
+            unsafe { core::arch::asm!("wfi") };
+        }
         trap::init();
+        vm::init_hart();
+        log!(Info, "Hart {} online...", hw::hartid());
+        // Take interrupts (IPIs at least) from here on.
+        intr_on();
     }
 
-    loop {}
+    sched::scheduler()
 }
@@ -0,0 +1,154 @@
+//! Stopping the machine on a kernel panic.
+//! The panicking hart halts every other hart through an IPI, so nothing
+//! keeps printing or touching shared state behind its back. Each halted hart
+//! records where it was before it stops, and the panicking hart prints all
+//! of it. What happens after that is chosen at build time: spin (default),
+//! or with the `panic-reboot` / `panic-poweroff` features reboot or power off,
+//! through SBI or the SiFive test device.
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+use crate::device::clint;
+#[cfg(not(feature = "sbi"))]
+use crate::device::finisher;
+use crate::hw::param::MAX_HARTS;
+use crate::hw::riscv::*;
+use crate::hw::{self, HartSet};
+use crate::ipi;
+use crate::time;
+
+/// What to do once everything is stopped and printed.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum PanicAction {
+    Spin,
+    Reboot,
+    Poweroff,
+}
+
+#[cfg(feature = "panic-reboot")]
+pub const PANIC_ACTION: PanicAction = PanicAction::Reboot;
+#[cfg(all(feature = "panic-poweroff", not(feature = "panic-reboot")))]
+pub const PANIC_ACTION: PanicAction = PanicAction::Poweroff;
+#[cfg(not(any(feature = "panic-reboot", feature = "panic-poweroff")))]
+pub const PANIC_ACTION: PanicAction = PanicAction::Spin;
+
+/// How long to wait for the other harts to check in, in nanoseconds.
+const HALT_TIMEOUT: u64 = time::NS_PER_SEC;
+
+/// Where a hart was when it stopped.
+#[derive(Copy, Clone, Debug)]
+pub struct HartDump {
+    pub sepc: usize,
+    pub scause: usize,
+    pub stval: usize,
+    pub sp: usize,
+    pub pid: Option<usize>,
+}
+
+/// Set by the first hart to panic.
+static PANICKING: AtomicBool = AtomicBool::new(false);
+/// One dump per hart, each written only by its own hart.
+static mut DUMPS: [HartDump; MAX_HARTS] = [HartDump {
+    sepc: 0,
+    scause: 0,
+    stval: 0,
+    sp: 0,
+    pid: None,
+}; MAX_HARTS];
+/// Harts whose entry in `DUMPS` is filled in.
+static DUMPED: AtomicUsize = AtomicUsize::new(0);
+
+/// Has some hart panicked?
+pub fn panicking() -> bool {
+    PANICKING.load(Ordering::Relaxed)
+}
+
+/// Record the calling hart's state, with `sp` as its stack pointer.
+fn record(sp: usize) {
+    let hart = hw::this_hart();
+    let dump = HartDump {
+        sepc: read_sepc(),
+        scause: read_scause() as usize,
+        stval: read_stval(),
+        sp,
+        pid: hart.peek_process(|p| p.id()),
+    };
+    unsafe { DUMPS[hart.id()] = dump };
+    DUMPED.fetch_or(1 << hart.id(), Ordering::Release);
+}
+
+/// Record where this hart was interrupted and stop. Runs in the IPI
+/// handler of every hart but the panicking one.
+pub fn park() -> ! {
+    // The trap vector left the interrupted stack pointer in sscratch.
+    record(read_sscratch());
+    ipi::halt();
+}
+
+/// Stop the other harts, print everyone's state, then spin, reboot or
+/// power off according to `PANIC_ACTION`. Called from the panic handler
+/// once the message is out.
+pub fn halt_all() -> ! {
+    intr_off();
+    if PANICKING.swap(true, Ordering::AcqRel) {
+        // Someone else is already at it, or we panicked while panicking.
+        record(read_sp());
+        ipi::halt();
+    }
+    record(read_sp());
+
+    let others = HartSet::others();
+    ipi::send(others, ipi::Message::Halt);
+    let deadline = clint::read_mtime() + time::ns_to_ticks(HALT_TIMEOUT);
+    while DUMPED.load(Ordering::Acquire) & others.bits() != others.bits()
+        && clint::read_mtime() < deadline
+    {
+        core::hint::spin_loop();
+    }
+
+    let dumped = HartSet::from_bits(DUMPED.load(Ordering::Acquire));
+    for hart in HartSet::all().iter() {
+        if !dumped.contains(hart) {
+            println!("hart {}: did not stop", hart);
+            continue;
+        }
+        let dump = unsafe { DUMPS[hart] };
+        let tag = if hart == hw::hartid() {
+            " (panicked)"
+        } else {
+            ""
+        };
+        println!(
+            "hart {}{}: sepc {:#x} scause {:#x} stval {:#x} sp {:#x} process {:?}",
+            hart, tag, dump.sepc, dump.scause, dump.stval, dump.sp, dump.pid
+        );
+    }
+
+    finish(PANIC_ACTION)
+}
+
+fn finish(action: PanicAction) -> ! {
+    match action {
+        PanicAction::Spin => {}
+        #[cfg(feature = "sbi")]
+        PanicAction::Reboot | PanicAction::Poweroff => {
+            use hw::sbi::{ResetReason, ResetType};
+            let kind = if action == PanicAction::Reboot {
+                ResetType::ColdReboot
+            } else {
+                ResetType::Shutdown
+            };
+            let err = hw::sbi::system_reset(kind, ResetReason::SystemFailure);
+            println!("{:?} failed: {:?}", action, err);
+        }
+        #[cfg(not(feature = "sbi"))]
+        PanicAction::Reboot | PanicAction::Poweroff if !finisher::present() => {
+            println!("{:?} needs a reset device, spinning instead", action);
+        }
+        #[cfg(not(feature = "sbi"))]
+        PanicAction::Reboot => finisher::reboot(),
+        // Power off with a failure code, so whatever runs QEMU sees the panic.
+        #[cfg(not(feature = "sbi"))]
+        PanicAction::Poweroff => finisher::exit_with_code(1),
+    }
+    ipi::halt()
+}
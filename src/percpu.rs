@@ -0,0 +1,155 @@
+//! Per-hart variables.
+//! ```ignore
+//! percpu! {
+//!     /// Timer interrupts taken on this hart.
+//!     static TICKS: u64 = 0;
+//! }
+//!
+//! *TICKS.get_mut() += 1;
+//! ```
+//! declares a `PerCpu<u64>` with one copy of the value per hart, each on a
+//! cache line of its own so harts don't fight over them. `get` and `get_mut`
+//! hand out the calling hart's copy, behind a guard that keeps interrupts
+//! off while it is alive: no trap handler can come in and touch the same
+//! copy, and the scheduler can't move us to another hart (`sched` refuses
+//! to switch out with interrupts pushed off), so the copy stays ours.
+//! Borrows are checked like a `RefCell`, a hart can't have a `get_mut` guard
+//! and any other guard to the same variable at once.
+use core::cell::{Cell, UnsafeCell};
+use core::ops::{Deref, DerefMut};
+
+use crate::hw::{self, param::MAX_HARTS};
+
+/// Declare per-hart statics. See the module docs.
+macro_rules! percpu {
+    ($($(#[$attr:meta])* $vis:vis static $name:ident: $ty:ty = $init:expr;)+) => {
+        $(
+            $(#[$attr])*
+            $vis static $name: $crate::percpu::PerCpu<$ty> = {
+                #[allow(clippy::declare_interior_mutable_const)]
+                const INIT: $crate::percpu::Slot<$ty> = $crate::percpu::Slot::new($init);
+                $crate::percpu::PerCpu::new([INIT; $crate::hw::param::MAX_HARTS])
+            };
+        )+
+    };
+}
+
+/// One hart's copy.
+#[repr(align(64))]
+pub struct Slot<T> {
+    value: UnsafeCell<T>,
+    /// Shared guards out, or -1 for a `get_mut` guard.
+    borrow: Cell<isize>,
+}
+
+impl<T> Slot<T> {
+    pub const fn new(value: T) -> Self {
+        Slot {
+            value: UnsafeCell::new(value),
+            borrow: Cell::new(0),
+        }
+    }
+}
+
+pub struct PerCpu<T> {
+    slots: [Slot<T>; MAX_HARTS],
+}
+
+// Each hart only gets at its own slot, with interrupts off, except
+// through the unsafe `remote`.
+unsafe impl<T: Send> Sync for PerCpu<T> {}
+
+/// Shared access to this hart's copy, from `PerCpu::get`.
+pub struct PerCpuRef<'a, T> {
+    slot: &'a Slot<T>,
+}
+
+/// Exclusive access to this hart's copy, from `PerCpu::get_mut`.
+pub struct PerCpuRefMut<'a, T> {
+    slot: &'a Slot<T>,
+}
+
+impl<T> PerCpu<T> {
+    /// Use `percpu!` instead.
+    pub const fn new(slots: [Slot<T>; MAX_HARTS]) -> Self {
+        PerCpu { slots }
+    }
+
+    /// Interrupts off, then this hart's slot.
+    fn this_slot(&self) -> &Slot<T> {
+        hw::push_off();
+        &self.slots[hw::hartid()]
+    }
+
+    /// This hart's copy, shared.
+    #[track_caller]
+    pub fn get(&self) -> PerCpuRef<T> {
+        let slot = self.this_slot();
+        if slot.borrow.get() < 0 {
+            hw::pop_off();
+            panic!("per-hart variable already borrowed mutably");
+        }
+        slot.borrow.set(slot.borrow.get() + 1);
+        PerCpuRef { slot }
+    }
+
+    /// This hart's copy, exclusive.
+    #[track_caller]
+    pub fn get_mut(&self) -> PerCpuRefMut<T> {
+        let slot = self.this_slot();
+        if slot.borrow.get() != 0 {
+            hw::pop_off();
+            panic!("per-hart variable already borrowed");
+        }
+        slot.borrow.set(-1);
+        PerCpuRefMut { slot }
+    }
+
+    /// Run `f` on this hart's copy.
+    pub fn with<R>(&self, f: impl FnOnce(&mut T) -> R) -> R {
+        f(&mut self.get_mut())
+    }
+
+    /// `hart`'s copy, from any hart.
+    ///
+    /// # Safety
+    /// Nothing may be changing that copy meanwhile: `T` is `Sync` and only
+    /// ever reached through `get` (atomic counters, say), or `hart` is stopped.
+    pub unsafe fn remote(&self, hart: usize) -> &T {
+        &*self.slots[hart].value.get()
+    }
+}
+
+impl<T> Deref for PerCpuRef<'_, T> {
+    type Target = T;
+    fn deref(&self) -> &T {
+        unsafe { &*self.slot.value.get() }
+    }
+}
+
+impl<T> Drop for PerCpuRef<'_, T> {
+    fn drop(&mut self) {
+        self.slot.borrow.set(self.slot.borrow.get() - 1);
+        hw::pop_off();
+    }
+}
+
+impl<T> Deref for PerCpuRefMut<'_, T> {
+    type Target = T;
+    fn deref(&self) -> &T {
+        unsafe { &*self.slot.value.get() }
+    }
+}
+
+impl<T> DerefMut for PerCpuRefMut<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.slot.value.get() }
+    }
+}
+
+impl<T> Drop for PerCpuRefMut<'_, T> {
+    fn drop(&mut self) {
+        self.slot.borrow.set(0);
+        hw::pop_off();
+    }
+}
@@ -0,0 +1,161 @@
+//! Process scheduling.
+//! Runnable processes wait in one run queue shared by all harts. Each hart
+//! runs `scheduler()`, which takes the next process off the queue and
+//! switches to it. A process gives the hart back with `yield_now` (it goes
+//! back on the run queue) or by blocking on a `WaitQueue` (it goes there).
+//! Reference: xv6-riscv/kernel/proc.c
+//!
+//! Processes are owned by whatever queue they are on, or by the hart running
+//! them. A process can't put itself on a queue while it still runs, so it
+//! leaves a `Handoff` for the scheduler, which acts on it once it is off the
+//! process' stack.
+//!
+//! `kthread` runs kernel code as processes of its own, and `workqueue` hands
+//! work from trap handlers to kernel threads. Harts with nothing to run wait
+//! in `idle`.
+pub mod idle;
+pub mod kthread;
+pub mod workqueue;
+
+use alloc::boxed::Box;
+use alloc::collections::VecDeque;
+
+use crate::hw::riscv::intr_on;
+use crate::hw::{self, swtch};
+use crate::lock::irq_mutex::IrqMutex;
+use crate::lock::wait::WaitQueue;
+use crate::vm::process::{Process, ProcessState};
+
+static RUN_QUEUE: IrqMutex<VecDeque<Box<Process>>> = IrqMutex::new(VecDeque::new());
+
+/// What to do with the process that just switched back to the scheduler.
+#[derive(Copy, Clone)]
+enum Handoff {
+    /// Still runnable, back on the run queue.
+    Yield,
+    /// Waiting on `queue`, unless it was woken since `seq`.
+    Park { queue: *const WaitQueue, seq: usize },
+    /// Done, free it.
+    Exit,
+}
+
+// The queue outlives the handoff, its waiter is blocked on it.
+unsafe impl Send for Handoff {}
+
+percpu! {
+    /// Handoff left by the process that is switching out.
+    static HANDOFF: Option<Handoff> = None;
+}
+
+/// Make `process` runnable.
+pub fn make_ready(mut process: Box<Process>) {
+    process.set_state(ProcessState::Ready);
+    RUN_QUEUE.lock().push_back(process);
+    idle::kick();
+}
+
+/// Is the caller a process (as opposed to boot code or the scheduler)?
+pub fn in_process() -> bool {
+    hw::this_hart().has_process()
+}
+
+/// Run processes on this hart forever.
+pub fn scheduler() -> ! {
+    loop {
+        // Let interrupts in now and then, or nothing could ever wake anyone up.
+        intr_on();
+
+        let next = RUN_QUEUE.lock().pop_front();
+        let mut process = match next {
+            Some(process) => process,
+            None => {
+                idle::idle(|| !RUN_QUEUE.lock().is_empty());
+                continue;
+            }
+        };
+
+        hw::push_off();
+        let hart = hw::this_hart();
+        process.set_state(ProcessState::Run);
+        process.activate();
+        let ctx = process.context() as *mut _;
+        hart.set_process(Some(process));
+        unsafe { swtch(hart.context(), ctx) };
+
+        // The process gave the hart back, see `switch_out`.
+        let hart = hw::this_hart();
+        let process = hart.set_process(None).expect("no process after switch");
+        process.deactivate();
+        let handoff = HANDOFF.get_mut().take();
+        hw::pop_off();
+        match handoff.expect("process switched out without a handoff") {
+            Handoff::Yield => make_ready(process),
+            Handoff::Park { queue, seq } => unsafe { (*queue).park(process, seq) },
+            Handoff::Exit => {
+                log!(
+                    Debug,
+                    "Process {} ({}) exited",
+                    process.id(),
+                    process.name()
+                );
+                drop(process);
+            }
+        }
+    }
+}
+
+/// Leave `handoff` for the scheduler and switch to it. Returns when the
+/// scheduler (on this or another hart) switches back to us.
+fn switch_out(handoff: Handoff) {
+    hw::push_off();
+    let hart = hw::this_hart();
+    // A trap handler runs on the hart's trap stack, which the next trap
+    // would reuse under us. Traps don't push_off, the check below misses it.
+    assert!(!hart.in_trap(), "switching out of a trap handler");
+    assert_eq!(hart.irq_depth(), 1, "switching out with locks held");
+    #[cfg(feature = "lockdep")]
+    crate::lock::lockdep::assert_none_held();
+    // Interrupt state belongs to the process, not the hart.
+    let enabled = hart.irq_enabled();
+    *HANDOFF.get_mut() = Some(handoff);
+    // Not borrowed across the switch, we may come back on another hart.
+    let ctx = hart
+        .process()
+        .expect("switch_out without a process")
+        .context() as *mut _;
+    unsafe { swtch(ctx, hart.context()) };
+
+    // Maybe on another hart now.
+    hw::this_hart().set_irq_enabled(enabled);
+    hw::pop_off();
+}
+
+/// Give up the hart, staying runnable.
+pub fn yield_now() {
+    if in_process() {
+        switch_out(Handoff::Yield);
+    }
+}
+
+/// End the calling process. The scheduler frees it once off its stack.
+pub fn exit() -> ! {
+    hw::this_hart()
+        .process()
+        .expect("exit without a process")
+        .set_state(ProcessState::Dead);
+    switch_out(Handoff::Exit);
+    unreachable!("exited process switched back in");
+}
+
+/// Block on `queue` unless it has been woken since the caller saw `seq`.
+/// Only for `WaitQueue`, which checks `in_process` first.
+pub(crate) fn block_on(queue: &WaitQueue, seq: usize) {
+    hw::this_hart()
+        .process()
+        .expect("block_on without a process")
+        .set_state(ProcessState::Wait);
+    switch_out(Handoff::Park {
+        queue: queue as *const WaitQueue,
+        seq,
+    });
+}
@@ -0,0 +1,89 @@
+//! Idling.
+//! A hart with nothing to run waits for an interrupt (`wfi`) instead of
+//! spinning on the run queue, so idle harts don't burn a host CPU under
+//! QEMU. Timer interrupts, IPIs and device interrupts all wake it up.
+//!
+//! The run queue is checked one last time with interrupts off and the hart
+//! marked idle: `wfi` still wakes up on an interrupt that comes in then, it
+//! just isn't taken until we turn interrupts back on. `make_ready` kicks an
+//! idle hart with a `Reschedule` IPI, since a process showing up on the run
+//! queue raises no interrupt of its own.
+use core::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+
+use crate::device::clint;
+use crate::hw::param::MAX_HARTS;
+use crate::hw::riscv::{intr_off, intr_on};
+use crate::hw::{self, HartSet};
+use crate::ipi;
+use crate::time;
+
+/// Harts waiting in `idle`, a `HartSet`.
+static IDLE: AtomicUsize = AtomicUsize::new(0);
+
+#[allow(clippy::declare_interior_mutable_const)]
+const ZERO: AtomicU64 = AtomicU64::new(0);
+/// mtime ticks each hart has spent in `idle`.
+static IDLE_TICKS: [AtomicU64; MAX_HARTS] = [ZERO; MAX_HARTS];
+
+/// Wait for an interrupt, unless `ready` says there is work already. Comes
+/// back with interrupts on, after taking whatever woke us up.
+pub fn idle(ready: impl Fn() -> bool) {
+    let hart = hw::hartid();
+    intr_off();
+    // Marked idle before the last look at the run queue, so `kick` either
+    // sees us idle, or we see what it queued.
+    IDLE.fetch_or(1 << hart, Ordering::SeqCst);
+    if !ready() {
+        let start = clint::read_mtime();
+        unsafe { core::arch::asm!("wfi") };
+        let ticks = clint::read_mtime().saturating_sub(start);
+        IDLE_TICKS[hart].fetch_add(ticks, Ordering::Relaxed);
+    }
+    IDLE.fetch_and(!(1 << hart), Ordering::SeqCst);
+    intr_on();
+}
+
+/// Wake one idle hart, if there is any, to look at the run queue. The
+/// hart is taken off `IDLE` right away, so the next kick wakes another one
+/// rather than poking the same hart before it is up.
+pub fn kick() {
+    let mut bits = IDLE.load(Ordering::SeqCst);
+    while let Some(hart) = HartSet::from_bits(bits).iter().next() {
+        match IDLE.compare_exchange(
+            bits,
+            bits & !(1 << hart),
+            Ordering::SeqCst,
+            Ordering::SeqCst,
+        ) {
+            Ok(_) => {
+                ipi::send(HartSet::single(hart), ipi::Message::Reschedule);
+                return;
+            }
+            Err(now) => bits = now,
+        }
+    }
+}
+
+/// Harts waiting for work right now.
+pub fn idle_harts() -> HartSet {
+    HartSet::from_bits(IDLE.load(Ordering::Relaxed))
+}
+
+/// Time `hart` has spent idle since boot, in nanoseconds.
+pub fn idle_ns(hart: usize) -> u64 {
+    time::ticks_to_ns(IDLE_TICKS[hart].load(Ordering::Relaxed))
+}
+
+/// Sleep in a kernel thread for a while, then check that the harts got
+/// some idle time in meanwhile.
+pub fn test_idle() {
+    let _ = crate::sched::kthread::spawn("test_idle", || {
+        crate::timer::sleep(time::ns_to_ticks(50_000_000));
+        let idle: u64 = HartSet::all().iter().map(idle_ns).sum();
+        assert!(idle > 0, "no hart ever went idle");
+        for hart in HartSet::all().iter() {
+            log!(Debug, "Hart {} idle for {}us", hart, idle_ns(hart) / 1_000);
+        }
+        log!(Debug, "Successful test of idle accounting...");
+    });
+}
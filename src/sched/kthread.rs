@@ -0,0 +1,49 @@
+//! Kernel threads.
+//! A kernel thread is a process that runs a kernel function in S-mode, on a
+//! stack of its own from the page pool, in the kernel's address space. It is
+//! scheduled like any other process, and may sleep on wait queues and locks
+//! that trap handlers can't. It ends when its function returns.
+use alloc::boxed::Box;
+
+use crate::hw;
+use crate::sched;
+use crate::vm::process::Process;
+use crate::vm::{self, VmError};
+
+/// Pages of stack each kernel thread gets.
+pub const KSTACK_PAGES: usize = 4;
+
+/// Start a kernel thread running `f`. Returns its process id.
+pub fn spawn<F: FnOnce() + Send + 'static>(name: &'static str, f: F) -> Result<usize, VmError> {
+    let kstack = vm::request_phys_page(KSTACK_PAGES)?;
+    let process = Process::new_kernel(name, kstack, Box::new(f), kthread_start as usize);
+    let id = process.id();
+    log!(Debug, "Spawned kernel thread {} ({})", id, name);
+    sched::make_ready(Box::new(process));
+    Ok(id)
+}
+
+/// Where a new kernel thread's first `swtch` returns to.
+extern "C" fn kthread_start() -> ! {
+    // The scheduler switched to us with interrupts pushed off, and there
+    // is no `switch_out` on this stack to undo that.
+    hw::pop_off();
+    let entry = hw::this_hart()
+        .process()
+        .and_then(|mut process| process.take_entry())
+        .expect("kernel thread started without an entry");
+    entry();
+    sched::exit()
+}
+
+/// Spawn a thread that hands some work to the shared work queue.
+pub fn test_kthread() {
+    spawn("kthread-test", || {
+        log!(Debug, "Kernel thread running on hart {}...", hw::hartid());
+        sched::yield_now();
+        sched::workqueue::schedule_work(|| {
+            log!(Debug, "Successful test of kernel threads and workqueue...")
+        });
+    })
+    .expect("Couldn't spawn test kernel thread");
+}
@@ -0,0 +1,88 @@
+//! Deferred work.
+//! Trap handlers must be quick and can't sleep, so anything slow (say
+//! filesystem writeback, or network RX processing) is queued as a closure
+//! on a `WorkQueue` and run later by the queue's kernel threads. Queueing is
+//! safe from trap handlers: it takes interrupt safe locks only, and the
+//! kernel heap is interrupt safe.
+//!
+//! `SYSTEM` is the shared queue, started by `init`, and `schedule_work`
+//! queues on it. Subsystems that don't want to wait behind everyone else's
+//! work can have a `WorkQueue` of their own.
+use alloc::boxed::Box;
+use alloc::collections::VecDeque;
+
+use crate::hw;
+use crate::lock::irq_mutex::IrqMutex;
+use crate::lock::wait::WaitQueue;
+use crate::sched::kthread;
+
+type Work = Box<dyn FnOnce() + Send>;
+
+pub struct WorkQueue {
+    name: &'static str,
+    items: IrqMutex<VecDeque<Work>>,
+    /// Workers sleep here while `items` is empty.
+    wait: WaitQueue,
+}
+
+/// The shared work queue.
+pub static SYSTEM: WorkQueue = WorkQueue::new("events");
+
+impl WorkQueue {
+    pub const fn new(name: &'static str) -> Self {
+        WorkQueue {
+            name,
+            items: IrqMutex::new(VecDeque::new()),
+            wait: WaitQueue::new(),
+        }
+    }
+
+    /// Spawn `workers` kernel threads to run this queue's work.
+    pub fn start(&'static self, workers: usize) {
+        for _ in 0..workers {
+            kthread::spawn(self.name, move || self.worker())
+                .expect("Couldn't spawn work queue worker");
+        }
+        log!(
+            Info,
+            "Started {} workers for work queue {}...",
+            workers,
+            self.name
+        );
+    }
+
+    /// Run `work` on one of the workers, after work queued before it.
+    pub fn queue<F: FnOnce() + Send + 'static>(&self, work: F) {
+        self.items.lock().push_back(Box::new(work));
+        self.wait.wake_one();
+    }
+
+    /// Number of items queued and not started yet.
+    pub fn pending(&self) -> usize {
+        self.items.lock().len()
+    }
+
+    fn worker(&self) -> ! {
+        loop {
+            let mut work = None;
+            self.wait.wait_until(|| {
+                work = self.items.lock().pop_front();
+                work.is_some()
+            });
+            if let Some(work) = work {
+                work();
+            }
+        }
+    }
+}
+
+/// Queue `work` on the shared queue.
+pub fn schedule_work<F: FnOnce() + Send + 'static>(work: F) {
+    SYSTEM.queue(work);
+}
+
+/// Start the shared queue with one worker per hart. Call once on hart 0,
+/// after `vm::init`.
+pub fn init() {
+    SYSTEM.start(hw::nharts());
+}
@@ -0,0 +1,83 @@
+//! System calls.
+//! Numbers and errors follow Linux on RISC-V, so a libc port can use them
+//! as they are. `trap::s_handler` calls `syscall` on an ecall from U-mode.
+//! They run in the trap handler, so nothing here may block, and no process
+//! has user mappings yet, so nothing here takes a pointer either.
+#[cfg(not(feature = "sbi"))]
+use crate::device::finisher;
+
+pub const SYS_REBOOT: usize = 142;
+
+pub const EIO: isize = 5;
+pub const EINVAL: isize = 22;
+pub const ENOSYS: isize = 38;
+
+/// `reboot` wants both magic numbers, so a stray call can't reboot.
+pub const REBOOT_MAGIC1: usize = 0xfee1dead;
+pub const REBOOT_MAGIC2: usize = 672274793;
+pub const REBOOT_CMD_RESTART: usize = 0x01234567;
+pub const REBOOT_CMD_HALT: usize = 0xcdef0123;
+pub const REBOOT_CMD_POWER_OFF: usize = 0x4321fedc;
+
+/// Run system call `num`. Returns the result, or minus an errno.
+pub fn syscall(num: usize, args: [usize; 6]) -> isize {
+    let ret = match num {
+        SYS_REBOOT => reboot(args[0], args[1], args[2]),
+        _ => {
+            log!(Warning, "Unknown system call {}", num);
+            Err(ENOSYS)
+        }
+    };
+    match ret {
+        Ok(ret) => ret,
+        Err(errno) => -errno,
+    }
+}
+
+/// Only returns on error. Halt and power off are the same thing here.
+fn reboot(magic1: usize, magic2: usize, cmd: usize) -> Result<isize, isize> {
+    if magic1 != REBOOT_MAGIC1 || magic2 != REBOOT_MAGIC2 {
+        return Err(EINVAL);
+    }
+    let restart = match cmd {
+        REBOOT_CMD_RESTART => true,
+        REBOOT_CMD_HALT | REBOOT_CMD_POWER_OFF => false,
+        _ => return Err(EINVAL),
+    };
+    if restart {
+        log!(Info, "Rebooting...");
+    } else {
+        log!(Info, "Powering off...");
+    }
+    reset(restart)
+}
+
+/// Ask the firmware, like `panic::finish` does.
+#[cfg(feature = "sbi")]
+fn reset(restart: bool) -> Result<isize, isize> {
+    use crate::hw::sbi::{self, ResetReason, ResetType, SbiError};
+    let kind = if restart {
+        ResetType::ColdReboot
+    } else {
+        ResetType::Shutdown
+    };
+    let err = sbi::system_reset(kind, ResetReason::None);
+    log!(Warning, "{:?} failed: {:?}", kind, err);
+    match err {
+        Err(SbiError::NotSupported) => Err(ENOSYS),
+        _ => Err(EIO),
+    }
+}
+
+#[cfg(not(feature = "sbi"))]
+fn reset(restart: bool) -> Result<isize, isize> {
+    if !finisher::present() {
+        log!(Warning, "No reset device");
+        return Err(ENOSYS);
+    }
+    if restart {
+        finisher::reboot()
+    } else {
+        finisher::poweroff()
+    }
+}
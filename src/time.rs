@@ -0,0 +1,100 @@
+//! Time keeping.
+//! Monotonic time counts from boot and comes from mtime, which ticks at the
+//! timebase frequency the device tree gives in `/cpus`. Wall clock time is
+//! monotonic time plus the Goldfish RTC's reading at boot, so it only costs
+//! an mtime read, and never jumps backwards on its own.
+use crate::device::{self, clint, rtc};
+use crate::hw;
+use crate::hw::fdt;
+use crate::hw::param::TIMEBASE_HZ;
+use crate::lock::seqlock::SeqLock;
+use crate::timer;
+
+pub const NS_PER_SEC: u64 = 1_000_000_000;
+
+#[derive(Copy, Clone)]
+struct Clock {
+    /// mtime ticks per second, `param::TIMEBASE_HZ` until `init`.
+    hz: u64,
+    /// Wall clock time at mtime zero, in nanoseconds since the Unix epoch.
+    /// Zero if we have no RTC.
+    realtime_offset: u64,
+}
+
+/// Read on every conversion, written once by `init`.
+static CLOCK: SeqLock<Clock> = SeqLock::new(Clock {
+    hz: TIMEBASE_HZ,
+    realtime_offset: 0,
+});
+
+/// Take the timebase from the device tree and read the wall clock off the
+/// RTC. Call once on hart 0, after `device::probe`.
+pub fn init() {
+    let timebase = fdt::boot_fdt()
+        .and_then(|fdt| fdt.find_node("/cpus"))
+        .and_then(|cpus| cpus.property("timebase-frequency"))
+        .and_then(|p| p.as_u32());
+    match timebase {
+        Some(hz) if hz != 0 => CLOCK.update(|clock| clock.hz = hz as u64),
+        _ => log!(
+            Warning,
+            "No timebase-frequency in the device tree, assuming {} Hz",
+            TIMEBASE_HZ
+        ),
+    }
+
+    if device::find("rtc").is_some() {
+        let offset = rtc::read_time_ns().saturating_sub(monotonic_ns());
+        CLOCK.update(|clock| clock.realtime_offset = offset);
+    } else {
+        log!(Warning, "No RTC, wall clock time starts at the epoch");
+    }
+    log!(
+        Info,
+        "Timebase {} Hz, wall clock {}s since the epoch...",
+        timebase_hz(),
+        realtime_ns() / NS_PER_SEC
+    );
+}
+
+/// mtime ticks per second.
+pub fn timebase_hz() -> u64 {
+    CLOCK.read().hz
+}
+
+pub fn ticks_to_ns(ticks: u64) -> u64 {
+    (ticks as u128 * NS_PER_SEC as u128 / timebase_hz() as u128) as u64
+}
+
+/// Rounds up, so waiting that many ticks waits at least `ns`.
+pub fn ns_to_ticks(ns: u64) -> u64 {
+    let hz = timebase_hz() as u128;
+    let ticks = (ns as u128 * hz + NS_PER_SEC as u128 - 1) / NS_PER_SEC as u128;
+    ticks.min(u64::MAX as u128) as u64
+}
+
+/// Nanoseconds since boot (well, since mtime started counting).
+pub fn monotonic_ns() -> u64 {
+    ticks_to_ns(clint::read_mtime())
+}
+
+/// Nanoseconds since the Unix epoch.
+pub fn realtime_ns() -> u64 {
+    CLOCK.read().realtime_offset.saturating_add(monotonic_ns())
+}
+
+/// Sleep for at least `ns`, see `timer::sleep_until`. Not in a trap
+/// handler: no timer fires there, and the hart can't be given up.
+pub fn sleep_ns(ns: u64) {
+    assert!(!hw::this_hart().in_trap(), "sleep in a trap handler");
+    timer::sleep_until(clint::read_mtime().saturating_add(ns_to_ticks(ns)));
+}
+
+pub fn test_time() {
+    let before = monotonic_ns();
+    let after = monotonic_ns();
+    assert!(after >= before, "monotonic time went backwards");
+    assert_eq!(ticks_to_ns(timebase_hz()), NS_PER_SEC);
+    assert_eq!(ns_to_ticks(NS_PER_SEC), timebase_hz());
+    log!(Debug, "Successful test of time conversions...");
+}
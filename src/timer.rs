@@ -0,0 +1,229 @@
+//! Kernel timers.
+//! A timer runs a callback once mtime reaches its deadline, once or every
+//! `period` ticks. Each hart keeps the timers armed on it ordered by
+//! deadline, and programs its timer compare register for the earliest one
+//! only, so a hart with nothing due sleeps undisturbed (tickless), instead of
+//! taking an interrupt at a fixed rate.
+//!
+//! Callbacks run in the timer interrupt, with interrupts off, on the hart
+//! that armed the timer. They may arm and cancel timers (their own included),
+//! but must be quick: hand anything slow to `sched::workqueue`.
+//!
+//! Without OpenSBI the timer interrupt is taken in M-mode: `m_handler` quiets
+//! it and passes it on as a supervisor software interrupt, and `s_handler`
+//! calls `handle` on every software interrupt. With OpenSBI the supervisor
+//! timer interrupt comes to us directly.
+use alloc::boxed::Box;
+use alloc::collections::BTreeMap;
+use alloc::sync::Arc;
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+
+use crate::device::clint;
+use crate::hw::param::MAX_HARTS;
+use crate::hw::{self, hartid};
+use crate::lock::irq_mutex::IrqMutex;
+use crate::lock::once::Once;
+use crate::lock::wait::WaitQueue;
+
+pub type Callback = Box<dyn FnMut() + Send>;
+
+/// Handle to an armed timer, for `cancel`.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct TimerId {
+    hart: usize,
+    id: u64,
+}
+
+struct Timer {
+    period: Option<u64>,
+    callback: Callback,
+}
+
+/// Timers armed on one hart.
+struct TimerQueue {
+    /// By (deadline, id), earliest first.
+    timers: BTreeMap<(u64, u64), Timer>,
+    /// Deadline of each armed timer, by id.
+    deadlines: BTreeMap<u64, u64>,
+    /// Timer whose callback is running, and whether it was cancelled since.
+    running: Option<(u64, bool)>,
+}
+
+impl TimerQueue {
+    const fn new() -> Self {
+        TimerQueue {
+            timers: BTreeMap::new(),
+            deadlines: BTreeMap::new(),
+            running: None,
+        }
+    }
+
+    fn insert(&mut self, id: u64, deadline: u64, timer: Timer) {
+        self.timers.insert((deadline, id), timer);
+        self.deadlines.insert(id, deadline);
+    }
+
+    fn next_deadline(&self) -> u64 {
+        self.timers
+            .keys()
+            .next()
+            .map_or(u64::MAX, |&(deadline, _)| deadline)
+    }
+}
+
+#[allow(clippy::declare_interior_mutable_const)]
+const EMPTY: IrqMutex<TimerQueue> = IrqMutex::new(TimerQueue::new());
+static QUEUES: [IrqMutex<TimerQueue>; MAX_HARTS] = [EMPTY; MAX_HARTS];
+
+static NEXT_ID: AtomicU64 = AtomicU64::new(1);
+
+/// Fire at mtime `deadline`.
+pub fn add_at(deadline: u64, callback: Callback) -> TimerId {
+    arm(deadline, None, callback)
+}
+
+/// Fire once, `ticks` from now.
+pub fn add_after(ticks: u64, callback: Callback) -> TimerId {
+    arm(clint::read_mtime().saturating_add(ticks), None, callback)
+}
+
+/// Fire every `period` ticks, starting `period` from now.
+pub fn add_periodic(period: u64, callback: Callback) -> TimerId {
+    assert!(period > 0, "zero timer period");
+    arm(
+        clint::read_mtime().saturating_add(period),
+        Some(period),
+        callback,
+    )
+}
+
+fn arm(deadline: u64, period: Option<u64>, callback: Callback) -> TimerId {
+    let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+    // Keep interrupts off so we stay on the hart whose queue we use.
+    hw::push_off();
+    let hart = hartid();
+    let mut queue = QUEUES[hart].lock();
+    queue.insert(id, deadline, Timer { period, callback });
+    program(queue.next_deadline());
+    drop(queue);
+    hw::pop_off();
+    TimerId { hart, id }
+}
+
+/// Disarm `timer`. Returns whether it was still armed. A callback that is
+/// running right now (on another hart, or our own caller) finishes, but a
+/// periodic timer won't fire again.
+pub fn cancel(timer: TimerId) -> bool {
+    let mut queue = QUEUES[timer.hart].lock();
+    if let Some(deadline) = queue.deadlines.remove(&timer.id) {
+        queue.timers.remove(&(deadline, timer.id));
+        // The compare may now be early, which only costs a spurious interrupt.
+        return true;
+    }
+    if let Some((id, ref mut cancelled)) = queue.running {
+        if id == timer.id {
+            *cancelled = true;
+        }
+    }
+    false
+}
+
+/// Run every timer on this hart that is due, then program the compare for
+/// the next one. Called from the timer interrupt.
+pub fn handle() {
+    let hart = hartid();
+    loop {
+        let now = clint::read_mtime();
+        let mut queue = QUEUES[hart].lock();
+        let key = match queue.timers.keys().next() {
+            Some(&(deadline, id)) if deadline <= now => (deadline, id),
+            _ => {
+                program(queue.next_deadline());
+                return;
+            }
+        };
+        let (deadline, id) = key;
+        let mut timer = queue.timers.remove(&key).unwrap();
+        queue.deadlines.remove(&id);
+        queue.running = Some((id, false));
+        // Unlocked, so the callback can arm and cancel timers.
+        drop(queue);
+
+        (timer.callback)();
+
+        let mut queue = QUEUES[hart].lock();
+        let cancelled = matches!(queue.running.take(), Some((_, true)));
+        if let (Some(period), false) = (timer.period, cancelled) {
+            // Stay on the original schedule, unless we fell behind it.
+            let mut next = deadline.saturating_add(period);
+            if next <= now {
+                next = now.saturating_add(period);
+            }
+            queue.insert(id, next, timer);
+        }
+    }
+}
+
+/// Set this hart's timer compare to `deadline`, `u64::MAX` for never.
+#[cfg(not(feature = "sbi"))]
+fn program(deadline: u64) {
+    clint::set_mtimecmp(hartid(), deadline);
+}
+
+#[cfg(feature = "sbi")]
+fn program(deadline: u64) {
+    let _ = hw::sbi::set_timer(deadline);
+}
+
+/// Sleep until mtime reaches `deadline`. Processes sleep, anything else spins,
+/// and needs interrupts on to ever wake up.
+pub fn sleep_until(deadline: u64) {
+    struct Sleeper {
+        done: AtomicBool,
+        queue: WaitQueue,
+    }
+    let sleeper = Arc::new(Sleeper {
+        done: AtomicBool::new(false),
+        queue: WaitQueue::new(),
+    });
+    let waker = sleeper.clone();
+    add_at(
+        deadline,
+        Box::new(move || {
+            waker.done.store(true, Ordering::Release);
+            waker.queue.wake_all();
+        }),
+    );
+    sleeper
+        .queue
+        .wait_until(|| sleeper.done.load(Ordering::Acquire));
+}
+
+/// Sleep for `ticks` of mtime, see `sleep_until`.
+pub fn sleep(ticks: u64) {
+    sleep_until(clint::read_mtime().saturating_add(ticks));
+}
+
+/// Arm a one-shot and a periodic timer, the periodic one cancels itself
+/// after a few rounds. They log as they fire, once interrupts are on.
+pub fn test_timer() {
+    use core::sync::atomic::AtomicUsize;
+    static ROUNDS: AtomicUsize = AtomicUsize::new(0);
+    static PERIODIC: Once<TimerId> = Once::new();
+
+    let tick = crate::time::ns_to_ticks(10_000_000);
+    add_after(
+        tick,
+        Box::new(|| log!(Debug, "Successful test of one-shot timer...")),
+    );
+    let periodic = add_periodic(
+        tick,
+        Box::new(|| {
+            if ROUNDS.fetch_add(1, Ordering::Relaxed) + 1 == 3 {
+                cancel(*PERIODIC.wait());
+                log!(Debug, "Successful test of periodic timer...");
+            }
+        }),
+    );
+    let _ = PERIODIC.set(periodic);
+}
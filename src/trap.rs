@@ -1,6 +1,10 @@
 //! Kernel trap handlers.
 use crate::device::clint;
+use crate::hw;
 use crate::hw::riscv;
+use crate::ipi;
+use crate::syscall;
+use crate::timer;
 use crate::vm::ptable::PageTable;
 
 use crate::log;
@@ -22,6 +26,18 @@ pub struct TrapFrame {
     regs: [usize; 32],
 }
 
+impl TrapFrame {
+    pub const fn new() -> Self {
+        TrapFrame {
+            kpgtbl: core::ptr::null_mut(),
+            handler: core::ptr::null(),
+            cause: 0,
+            retpc: 0,
+            regs: [0; 32],
+        }
+    }
+}
+
 /// Write the supervisor trap vector to stvec register on each hart.
 pub fn init() {
     riscv::write_stvec(__strapvec as usize);
@@ -34,30 +50,76 @@ pub extern "C" fn m_handler() {
 
     match mcause {
         riscv::MSTATUS_TIMER => {
-            // log::log!(Debug, "Machine timer interupt, hart: {}", riscv::read_mhartid());
-            clint::set_mtimecmp(10_000_000);
+            // Quiet the timer and pass it on to S-mode, where `timer::handle`
+            // runs what is due and sets the next deadline.
+            clint::set_mtimecmp(riscv::read_mhartid() as usize, u64::MAX);
+            riscv::write_mip(riscv::read_mip() | riscv::MIP_SSIP);
+        }
+        riscv::MCAUSE_SOFT => {
+            // An IPI sent through the CLINT, pass it on to S-mode.
+            clint::clear_msip(riscv::read_mhartid() as usize);
+            riscv::write_mip(riscv::read_mip() | riscv::MIP_SSIP);
         }
         _ => {
             log::log!(
                 Warning,
-                "Uncaught machine mode interupt. mcause: 0x{:x}",
-                mcause
+                "Uncaught machine mode interupt. mcause: 0x{:x} mepc: 0x{:x}",
+                mcause,
+                riscv::read_mepc()
             );
             panic!();
         }
     }
 }
 
+/// Registers of the trapped code, as `__strapvec` saved them: x0 to x31.
+/// Changes are loaded back on return, except to tp.
+#[repr(C)]
+pub struct TrapRegs {
+    pub x: [usize; 32],
+}
+
 /// Supervisor mode trap handler.
 #[no_mangle]
-pub extern "C" fn s_handler() {
-    let cause = riscv::read_scause();
+pub extern "C" fn s_handler(regs: &mut TrapRegs) {
+    hw::this_hart().enter_trap();
+    s_dispatch(riscv::read_scause(), regs);
+    hw::this_hart().leave_trap();
+}
+
+fn s_dispatch(cause: u64, regs: &mut TrapRegs) {
+    if cause == riscv::SCAUSE_ECALL_U {
+        // a7 is the call number, a0-a5 the arguments, the result goes in a0.
+        let args = [
+            regs.x[10], regs.x[11], regs.x[12], regs.x[13], regs.x[14], regs.x[15],
+        ];
+        regs.x[10] = syscall::syscall(regs.x[17], args) as usize;
+        riscv::write_sepc(riscv::read_sepc() + 4);
+        return;
+    }
+
+    if cause == riscv::SCAUSE_SOFT {
+        ipi::handle();
+        // May be a timer interrupt forwarded by `m_handler`.
+        #[cfg(not(feature = "sbi"))]
+        timer::handle();
+        return;
+    }
+
+    // Only reachable under OpenSBI, otherwise timer interrupts go to m_handler.
+    #[cfg(feature = "sbi")]
+    if cause == riscv::SCAUSE_TIMER {
+        timer::handle();
+        return;
+    }
 
     {
         log::log!(
             Warning,
-            "Uncaught supervisor mode interupt. scause: 0x{:x}",
-            cause
+            "Uncaught supervisor mode interupt. scause: 0x{:x} sepc: 0x{:x} stval: 0x{:x}",
+            cause,
+            riscv::read_sepc(),
+            riscv::read_stval()
         );
         panic!()
     }
@@ -1,49 +1,71 @@
 //! Virtual Memory
+pub mod asid;
 pub mod global;
+#[cfg(feature = "heap-debug")]
+pub mod heapdbg;
+pub mod memmap;
 mod palloc;
 pub mod process;
 pub mod ptable;
+pub mod tlb;
 pub mod vmalloc;
 
 use crate::hw::param::*;
+use crate::lock::once::Once;
 use alloc::boxed::Box;
 use core::alloc::{GlobalAlloc, Layout};
-use core::cell::OnceCell;
 
 use global::Galloc;
+#[cfg(feature = "heap-debug")]
+pub use heapdbg::heap_report;
 use palloc::*;
 use process::Process;
-use ptable::kpage_init; //, PageTable};
+use ptable::{kpage_init, PageTable};
+use vmalloc::KallocStats;
+
+pub use palloc::PageStats;
 
 /// Global physical page pool allocated by the kernel physical allocator.
-static mut PAGEPOOL: OnceCell<PagePool> = OnceCell::new();
+static PAGEPOOL: Once<PagePool> = Once::new();
 #[global_allocator]
-static mut GLOBAL: GlobalWrapper = GlobalWrapper {
-    inner: OnceCell::new(),
-};
+static GLOBAL: GlobalWrapper = GlobalWrapper { inner: Once::new() };
+
+/// Kernel page table, loaded by every hart.
+static KPGTABLE: Once<PageTable> = Once::new();
 
 struct GlobalWrapper {
-    inner: OnceCell<Galloc>,
+    inner: Once<Galloc>,
+}
+
+impl GlobalWrapper {
+    fn galloc(&self) -> &Galloc {
+        self.inner.get().expect("kernel heap used before vm::init")
+    }
 }
 
 unsafe impl GlobalAlloc for GlobalWrapper {
     unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
-        self.inner.get().unwrap().alloc(layout)
+        self.galloc().alloc(layout)
     }
 
     unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
-        self.inner.get().unwrap().dealloc(ptr, layout)
+        self.galloc().dealloc(ptr, layout)
     }
 
     unsafe fn alloc_zeroed(&self, layout: Layout) -> *mut u8 {
-        self.inner.get().unwrap().alloc_zeroed(layout)
+        self.galloc().alloc_zeroed(layout)
     }
 
     unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
-        self.inner.get().unwrap().realloc(ptr, layout, new_size)
+        self.galloc().realloc(ptr, layout, new_size)
     }
 }
 
+/// The kernel page pool, once `init` has set it up.
+fn pagepool() -> &'static PagePool {
+    PAGEPOOL.get().expect("page pool used before vm::init")
+}
+
 /// (Still growing) list of kernel VM system error cases.
 #[derive(Debug)]
 pub enum VmError {
@@ -55,6 +77,40 @@ pub enum VmError {
     Koom,
 }
 
+/// Snapshot of kernel memory usage, see `stats()`.
+#[derive(Copy, Clone, Debug)]
+pub struct MemStats {
+    pub pages: PageStats,
+    pub kalloc: KallocStats,
+}
+
+impl core::fmt::Display for MemStats {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        let p = &self.pages;
+        write!(
+            f,
+            "pages: {} total, {} used, {} free, {} high water, {} largest free extent\r\n",
+            p.total, p.used, p.free, p.high_water, p.largest_free_extent
+        )?;
+        let k = &self.kalloc;
+        write!(
+            f,
+            "kalloc: {} zones ({} high water), {} bytes in use ({} high water)",
+            k.zones, k.zones_high_water, k.bytes_in_use, k.bytes_high_water
+        )?;
+        for (max, class) in vmalloc::SIZE_CLASSES.iter().zip(k.classes.iter()) {
+            if class.total_allocs != 0 {
+                write!(
+                    f,
+                    "\r\n    <= {:4} bytes: {} in use, {} high water, {} allocs",
+                    max, class.in_use, class.high_water, class.total_allocs
+                )?;
+            }
+        }
+        Ok(())
+    }
+}
+
 /// Moving to `mod process`
 pub trait Resource {}
 
@@ -72,34 +128,53 @@ pub struct TaskNode {
 
 /// Initialize the kernel VM system.
 /// First, setup the kernel physical page pool.
-/// We start the pool at the end of the .bss section, and stop at the end of physical memory.
+/// The pool covers the usable RAM ranges from the device tree (see `memmap`), which
+/// start no lower than the end of the .bss section.
 /// Next, we map physical memory into the kernel's physical memory 1:1.
 /// Next, initialize the kernel virtual memory allocator pool.
 /// Finally we set the global kernel page table `KPGTABLE` variable to point to the
 /// kernel's page table struct.
 pub fn init() -> Result<(), PagePool> {
-    unsafe {
-        match PAGEPOOL.set(PagePool::new(bss_end(), dram_end())) {
-            Ok(_) => {}
-            Err(_) => {
-                panic!("vm double init.")
-            }
+    let usable = memmap::usable();
+    for range in usable.ranges() {
+        log!(
+            Debug,
+            "Usable physical memory: {:#x}..{:#x} ({} KiB)",
+            range.start,
+            range.end,
+            range.len() / 1024
+        );
+    }
+    let (first, rest) = usable
+        .ranges()
+        .split_first()
+        .expect("No usable physical memory.");
+    let pool = PagePool::new(first.start as *mut usize, first.end as *mut usize);
+    for range in rest {
+        pool.add_range(range.start as *mut usize, range.end as *mut usize);
+    }
+    match PAGEPOOL.set(pool) {
+        Ok(_) => {}
+        Err(_) => {
+            panic!("vm double init.")
         }
     }
     log!(Debug, "Successfully initialized kernel page pool...");
 
-    unsafe {
-        match GLOBAL.inner.set(Galloc::new(PAGEPOOL.get_mut().unwrap())) {
-            Ok(_) => {}
-            Err(_) => {
-                panic!("vm double init.")
-            }
+    match GLOBAL.inner.set(Galloc::new(pagepool())) {
+        Ok(_) => {}
+        Err(_) => {
+            panic!("vm double init.")
         }
     }
 
     // Map text, data, stacks, heap into kernel page table.
-    match kpage_init() {
-        Ok(pt) => pt.write_satp(),
+    match kpage_init(&memmap::dram()) {
+        Ok(pt) => {
+            let _ = KPGTABLE.set(pt);
+            pt.write_satp();
+            asid::init();
+        }
         Err(_) => {
             panic!();
         }
@@ -107,17 +182,31 @@ pub fn init() -> Result<(), PagePool> {
     Ok(())
 }
 
+/// Turn on paging with the kernel page table on a secondary hart.
+/// Only valid once hart 0 has finished `init`.
+pub fn init_hart() {
+    KPGTABLE
+        .get()
+        .expect("vm::init_hart before vm::init")
+        .write_satp();
+}
+
+/// The kernel page table, shared by every hart and kernel thread.
+pub fn kernel_page_table() -> PageTable {
+    *KPGTABLE.get().expect("kernel page table used before vm::init")
+}
+
 /// A test designed to be used with GDB.
 /// Allocate A, then B. Free A, then B.
 pub unsafe fn test_palloc() {
-    let one = PAGEPOOL.get_mut().unwrap().palloc().unwrap();
+    let one = pagepool().palloc().unwrap();
     one.addr.write(0xdeadbeaf);
 
-    let many = PAGEPOOL.get_mut().unwrap().palloc_plural(5).unwrap();
+    let many = pagepool().palloc_plural(5).unwrap();
     many.write_bytes(5, 512 * 2);
 
-    let _ = PAGEPOOL.get_mut().unwrap().pfree(one);
-    let _ = PAGEPOOL.get_mut().unwrap().pfree_plural(many, 5);
+    let _ = pagepool().pfree(one);
+    let _ = pagepool().pfree_plural(many, 5);
 
     log!(Debug, "Successful test of page allocation and freeing...");
 }
@@ -157,11 +246,11 @@ pub unsafe fn test_galloc() {
 
 // for internal vm use only.
 fn palloc() -> Result<Page, VmError> {
-    unsafe { PAGEPOOL.get_mut().unwrap().palloc() }
+    pagepool().palloc()
 }
 
 fn pfree(page: Page) -> Result<(), VmError> {
-    unsafe { PAGEPOOL.get_mut().unwrap().pfree(page) }
+    pagepool().pfree(page)
 }
 
 
@@ -188,11 +277,10 @@ impl PhysPageExtent {
 
 impl Drop for PhysPageExtent {
     fn drop(&mut self) {
-        unsafe {
-            match PAGEPOOL.get_mut().unwrap()
-                .pfree_plural(self.head.addr, self.num) {
-                    Ok(_) => {},
-                    Err(e) => {panic!("Double palloc free! {:?}", e)}
+        match pagepool().pfree_plural(self.head.addr, self.num) {
+            Ok(_) => {}
+            Err(e) => {
+                panic!("Double palloc free! {:?}", e)
             }
         }
     }
@@ -202,15 +290,21 @@ unsafe impl Send for PhysPageExtent {}
 
 /// Should be one and only way to get physical pages outside of vm module/subsystem.
 pub fn request_phys_page(num: usize) -> Result<PhysPageExtent, VmError>{
-    let addr = unsafe {
-        PAGEPOOL.get_mut().unwrap().palloc_plural(num)?
-    };
+    let addr = pagepool().palloc_plural(num)?;
     Ok(PhysPageExtent {
         head: Page::from(addr),
         num,
     })
 }
 
+/// Current page pool and kernel heap usage.
+pub fn stats() -> MemStats {
+    MemStats {
+        pages: pagepool().stats(),
+        kalloc: GLOBAL.galloc().small_pool_stats(),
+    }
+}
+
 pub fn test_phys_page() {
     {
         let _ = request_phys_page(1).unwrap();
@@ -0,0 +1,115 @@
+//! Address space identifiers.
+//! Tagging TLB entries with an ASID lets us switch page tables without
+//! flushing the whole TLB. Hardware implements anywhere from 0 to 16 ASID
+//! bits, which we probe at boot. ASID 0 is kept for the kernel page table.
+//!
+//! ASIDs are handed out in generations. Each address space remembers the
+//! generation its ASID came from; once every ASID of the current generation
+//! is taken we start a new one, and every hart flushes its TLB before it
+//! next loads an address space. Address spaces from an old generation get a
+//! fresh ASID the next time they are switched to.
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use crate::hw::riscv::*;
+use crate::hw::{self, HartSet};
+use crate::lock::mutex::Mutex;
+
+struct AsidAllocator {
+    /// Implemented ASID bits, zero when there is no ASID support.
+    bits: usize,
+    /// Current generation, starting at 1 so a zero context is never valid.
+    generation: usize,
+    /// Next free ASID in this generation.
+    next: usize,
+}
+
+static ASIDS: Mutex<AsidAllocator> = Mutex::new(AsidAllocator {
+    bits: 0,
+    generation: 1,
+    next: 1,
+});
+
+/// Harts that must flush their TLB before loading an ASID from the
+/// current generation.
+static NEEDS_FLUSH: AtomicUsize = AtomicUsize::new(0);
+
+/// ASID of one address space: its generation shifted up past `SATP_ASID_MASK`,
+/// or'ed with the ASID itself. Zero until first assigned.
+pub struct Asid {
+    context: AtomicUsize,
+}
+
+impl Asid {
+    pub const fn new() -> Self {
+        Asid {
+            context: AtomicUsize::new(0),
+        }
+    }
+}
+
+const GENERATION_SHIFT: usize = 16;
+
+/// Find out how many ASID bits the hardware keeps. Run on hart 0 with the
+/// kernel page table loaded: satp.ASID is WARL, so we set every bit and see
+/// which stick, then put the old value back.
+pub fn init() {
+    let old = read_satp();
+    write_satp(old | (SATP_ASID_MASK << SATP_ASID_SHIFT));
+    let bits = ((read_satp() >> SATP_ASID_SHIFT) & SATP_ASID_MASK).count_ones() as usize;
+    write_satp(old);
+    flush_tlb();
+
+    ASIDS.lock().bits = bits;
+    log!(Info, "{} ASID bits implemented...", bits);
+}
+
+/// Number of usable ASID bits, see `init`.
+pub fn bits() -> usize {
+    ASIDS.lock().bits
+}
+
+/// Hardware ASID to load for `asid` on this hart, assigning a new one if
+/// it has none from the current generation. Flushes the local TLB as needed,
+/// so the result can go straight into satp.
+pub fn assign(asid: &Asid) -> usize {
+    let mut alloc = ASIDS.lock();
+    if alloc.bits == 0 {
+        // Everything shares ASID 0, flush on every switch like before.
+        drop(alloc);
+        flush_tlb();
+        return 0;
+    }
+
+    let context = asid.context.load(Ordering::Relaxed);
+    let id = if context >> GENERATION_SHIFT == alloc.generation {
+        context & SATP_ASID_MASK
+    } else {
+        if alloc.next == 1 << alloc.bits {
+            // Out of ASIDs, start over and have every hart flush.
+            alloc.generation += 1;
+            alloc.next = 1;
+            NEEDS_FLUSH.store(HartSet::all().bits(), Ordering::Release);
+            log!(Debug, "ASID generation rolled over to {}", alloc.generation);
+        }
+        let id = alloc.next;
+        alloc.next += 1;
+        asid.context
+            .store(alloc.generation << GENERATION_SHIFT | id, Ordering::Relaxed);
+        id
+    };
+    drop(alloc);
+
+    let me = 1 << hw::hartid();
+    if NEEDS_FLUSH.fetch_and(!me, Ordering::AcqRel) & me != 0 {
+        flush_tlb();
+    }
+    id
+}
+
+/// Hardware ASID currently held by `asid`, if it is from this generation.
+pub fn current(asid: &Asid) -> Option<usize> {
+    let alloc = ASIDS.lock();
+    let context = asid.context.load(Ordering::Relaxed);
+    (alloc.bits != 0 && context >> GENERATION_SHIFT == alloc.generation)
+        .then_some(context & SATP_ASID_MASK)
+}
@@ -1,27 +1,37 @@
+use crate::lock::irq_mutex::IrqMutex;
 use crate::param::PAGE_SIZE;
+#[cfg(feature = "heap-debug")]
+use crate::vm::heapdbg;
 use crate::vm::palloc::PagePool;
-use crate::vm::vmalloc::{Kalloc, MAX_CHUNK_SIZE};
+use crate::vm::vmalloc::{Kalloc, KallocStats, MAX_CHUNK_SIZE};
 /// Global allocator on top of vmalloc and palloc
 use core::alloc::{GlobalAlloc, Layout};
-use core::cell::UnsafeCell;
 
 pub struct Galloc {
-    pool: *mut PagePool,
-    small_pool: UnsafeCell<Kalloc>,
+    pool: &'static PagePool,
+    /// Interrupt safe, so trap handlers can allocate too.
+    small_pool: IrqMutex<Kalloc>,
 }
 
 impl Galloc {
-    pub fn new(pool: &mut PagePool) -> Self {
+    pub fn new(pool: &'static PagePool) -> Self {
         let small_pool_start = pool
             .palloc()
             .expect("Could not initalize GlobalAlloc small pool");
         Galloc {
             pool,
-            small_pool: UnsafeCell::new(Kalloc::new(small_pool_start)),
+            small_pool: IrqMutex::new(Kalloc::new(small_pool_start)),
         }
     }
 }
 
+impl Galloc {
+    /// Usage counters of the sub-page allocator.
+    pub fn small_pool_stats(&self) -> KallocStats {
+        self.small_pool.lock().stats()
+    }
+}
+
 impl Drop for Galloc {
     fn drop(&mut self) {
         panic!("Dropped the general allocator")
@@ -50,8 +60,9 @@ fn decide_internal_scheme(layout: Layout) -> usize {
     }
 }
 
-unsafe impl GlobalAlloc for Galloc {
-    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+impl Galloc {
+    /// Hand out memory straight from the sub-page or page allocator.
+    unsafe fn raw_alloc(&self, layout: Layout) -> *mut u8 {
         if layout.align() > PAGE_SIZE {
             panic!("Page+ alignemnt requested in alloc");
         }
@@ -59,14 +70,14 @@ unsafe impl GlobalAlloc for Galloc {
         let num_pages = decide_internal_scheme(layout);
 
         if num_pages == 0 {
-            match (*self.small_pool.get()).alloc(layout.size()) {
+            match self.small_pool.lock().alloc(layout.size()) {
                 Ok(ptr) => ptr as *mut u8,
                 Err(e) => {
                     panic!("Small allocation failed {:?}", e)
                 }
             }
         } else {
-            match (*self.pool).palloc_plural(num_pages) {
+            match self.pool.palloc_plural(num_pages) {
                 Ok(ptr) => ptr as *mut u8,
                 Err(e) => {
                     panic!("Page allocation failed {:?}", e)
@@ -75,7 +86,8 @@ unsafe impl GlobalAlloc for Galloc {
         }
     }
 
-    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+    /// Return memory from `raw_alloc` to whichever allocator it came from.
+    unsafe fn raw_dealloc(&self, ptr: *mut u8, layout: Layout) {
         if layout.align() > PAGE_SIZE {
             panic!("Page+ alignemnt requested in dealloc");
         }
@@ -83,9 +95,9 @@ unsafe impl GlobalAlloc for Galloc {
         let num_pages = decide_internal_scheme(layout);
 
         if num_pages == 0 {
-            (*self.small_pool.get()).free(ptr as *mut usize)
+            self.small_pool.lock().free(ptr as *mut usize)
         } else {
-            match (*self.pool).pfree_plural(ptr as *mut usize, num_pages) {
+            match self.pool.pfree_plural(ptr as *mut usize, num_pages) {
                 Ok(_) => {}
                 Err(e) => {
                     panic!("Page deallocation failed {:?}", e)
@@ -93,12 +105,39 @@ unsafe impl GlobalAlloc for Galloc {
             }
         }
     }
+}
+
+unsafe impl GlobalAlloc for Galloc {
+    #[cfg(not(feature = "heap-debug"))]
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        self.raw_alloc(layout)
+    }
+
+    #[cfg(not(feature = "heap-debug"))]
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        self.raw_dealloc(ptr, layout)
+    }
+
+    /// Wrap the allocation in redzones and track it. See `vm::heapdbg`.
+    #[cfg(feature = "heap-debug")]
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let base = self.raw_alloc(heapdbg::outer_layout(layout));
+        heapdbg::track_alloc(base, layout)
+    }
+
+    /// Check redzones, poison and untrack the allocation. See `vm::heapdbg`.
+    #[cfg(feature = "heap-debug")]
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        let base = heapdbg::track_free(ptr, layout);
+        self.raw_dealloc(base, heapdbg::outer_layout(layout))
+    }
 
     unsafe fn alloc_zeroed(&self, layout: Layout) -> *mut u8 {
         let out = self.alloc(layout);
 
         let num_pages = decide_internal_scheme(layout);
-        if num_pages == 0 {
+        if num_pages == 0 || cfg!(feature = "heap-debug") {
+            // heap-debug poisons fresh allocations, so always zero there.
             out.write_bytes(0, layout.size());
             out
         } else {
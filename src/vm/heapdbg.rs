@@ -0,0 +1,238 @@
+//! Heap debugging mode (`heap-debug` feature).
+//!
+//! Every allocation made through `Galloc` is wrapped like so:
+//! ```text
+//! ┌──────────────┬─────────┬──────────────────────┬─────────┐
+//! │ AllocRecord  │ redzone │ user data (size)     │ redzone │
+//! └──────────────┴─────────┴──────────────────────┴─────────┘
+//! ▲ base (from Kalloc/palloc)   ▲ returned to caller
+//! ```
+//! Redzones are filled with `REDZONE_BYTE` and checked on free. Fresh
+//! allocations are filled with `ALLOC_POISON` and freed ones with
+//! `FREE_POISON`, so use of uninitialized or freed memory shows up as a
+//! recognizable pattern in gdb. Live allocations are kept on an intrusive
+//! list (the records themselves) so `heap_report()` can list them along with
+//! the return addresses of whoever allocated them.
+//!
+//! A freed record is marked `MAGIC_FREED` to catch double frees, but freeing
+//! pages zeroes them, record and all. So the bases of the last `FREED_SLOTS`
+//! freed allocations are remembered too, until the memory is handed out again.
+//!
+//! Call sites are recovered by walking the frame pointer chain, see `backtrace`.
+use core::alloc::Layout;
+use core::mem::{align_of, size_of};
+use core::ptr::null_mut;
+
+use crate::backtrace;
+use crate::lock::irq_mutex::IrqMutex;
+
+/// Bytes of redzone on each side of the user data.
+pub const REDZONE_SIZE: usize = 16;
+/// Number of return addresses recorded per allocation.
+pub const TRACE_DEPTH: usize = 6;
+
+const REDZONE_BYTE: u8 = 0xfd;
+const ALLOC_POISON: u8 = 0xa5;
+const FREE_POISON: u8 = 0x6b;
+
+const MAGIC_LIVE: usize = 0xa110_ca7e_d0d0_cafe;
+const MAGIC_FREED: usize = 0xf4ee_d0d0_dead_beef;
+
+/// Number of recently freed allocations remembered for double free checks.
+const FREED_SLOTS: usize = 64;
+
+/// Bookkeeping stored in front of every debug allocation.
+#[repr(C)]
+struct AllocRecord {
+    magic: usize,
+    size: usize,
+    seq: usize, // Allocation sequence number, for telling allocations apart.
+    prev: *mut AllocRecord,
+    next: *mut AllocRecord,
+    callers: [usize; TRACE_DEPTH],
+}
+
+/// Head of the live allocation list plus some running totals.
+struct LiveList {
+    head: *mut AllocRecord,
+    count: usize,
+    bytes: usize,
+    seq: usize,
+    /// Bases of recently freed allocations, zero for an empty slot.
+    freed: [usize; FREED_SLOTS],
+    freed_next: usize,
+}
+
+unsafe impl Send for LiveList {}
+
+static LIVE: IrqMutex<LiveList> = IrqMutex::new(LiveList {
+    head: null_mut(),
+    count: 0,
+    bytes: 0,
+    seq: 0,
+    freed: [0; FREED_SLOTS],
+    freed_next: 0,
+});
+
+/// Offset from the start of the underlying allocation to the user data.
+fn data_offset(layout: Layout) -> usize {
+    let align = layout.align().max(align_of::<AllocRecord>());
+    (size_of::<AllocRecord>() + REDZONE_SIZE + align - 1) & !(align - 1)
+}
+
+/// Layout to request from the real allocator in order to fit the record,
+/// both redzones and the user data for `layout`.
+pub fn outer_layout(layout: Layout) -> Layout {
+    let align = layout.align().max(align_of::<AllocRecord>());
+    Layout::from_size_align(data_offset(layout) + layout.size() + REDZONE_SIZE, align)
+        .expect("heap debug layout overflow")
+}
+
+unsafe fn record_of(data: *mut u8) -> *mut AllocRecord {
+    data.byte_sub(REDZONE_SIZE + size_of::<AllocRecord>())
+        .cast()
+}
+
+/// Collect up to `TRACE_DEPTH` return addresses, see `backtrace::walk`.
+/// The first few belong to the allocator itself (`Galloc`, the `alloc`
+/// crate shims), the rest to whoever asked for memory.
+fn caller_trace() -> [usize; TRACE_DEPTH] {
+    let mut trace = [0; TRACE_DEPTH];
+    backtrace::capture(&mut trace);
+    trace
+}
+
+/// Set up a fresh allocation. `base` was returned by the real allocator for
+/// `outer_layout(layout)`. Returns the pointer to hand back to the caller.
+pub unsafe fn track_alloc(base: *mut u8, layout: Layout) -> *mut u8 {
+    let data = base.byte_add(data_offset(layout));
+    let record = record_of(data);
+
+    data.byte_sub(REDZONE_SIZE)
+        .write_bytes(REDZONE_BYTE, REDZONE_SIZE);
+    data.byte_add(layout.size())
+        .write_bytes(REDZONE_BYTE, REDZONE_SIZE);
+    data.write_bytes(ALLOC_POISON, layout.size());
+
+    let mut live = LIVE.lock();
+    // The memory is live again, it can be freed once more.
+    let end = base as usize + outer_layout(layout).size();
+    for slot in live.freed.iter_mut() {
+        if (base as usize..end).contains(slot) {
+            *slot = 0;
+        }
+    }
+    live.seq += 1;
+    record.write(AllocRecord {
+        magic: MAGIC_LIVE,
+        size: layout.size(),
+        seq: live.seq,
+        prev: null_mut(),
+        next: live.head,
+        callers: caller_trace(),
+    });
+    if !live.head.is_null() {
+        (*live.head).prev = record;
+    }
+    live.head = record;
+    live.count += 1;
+    live.bytes += layout.size();
+
+    data
+}
+
+/// Check and tear down an allocation. Panics on double free, a corrupted
+/// record or a clobbered redzone. Returns the base pointer to give back to
+/// the real allocator.
+pub unsafe fn track_free(data: *mut u8, layout: Layout) -> *mut u8 {
+    let record = record_of(data);
+    let base = data.byte_sub(data_offset(layout));
+    let freed = LIVE.lock().freed.contains(&(base as usize));
+    if freed {
+        panic!("Heap double free of {:?}", data);
+    }
+    match (*record).magic {
+        MAGIC_LIVE => {}
+        MAGIC_FREED => panic!("Heap double free of {:?}", data),
+        magic => panic!(
+            "Heap free of {:?} with bad record magic 0x{:x}",
+            data, magic
+        ),
+    }
+    if (*record).size != layout.size() {
+        panic!(
+            "Heap free of {:?} with size {}, but it was allocated with size {}",
+            data,
+            layout.size(),
+            (*record).size
+        );
+    }
+    check_redzone(data.byte_sub(REDZONE_SIZE), data, "front");
+    check_redzone(data.byte_add(layout.size()), data, "back");
+
+    let mut live = LIVE.lock();
+    let (prev, next) = ((*record).prev, (*record).next);
+    if prev.is_null() {
+        live.head = next;
+    } else {
+        (*prev).next = next;
+    }
+    if !next.is_null() {
+        (*next).prev = prev;
+    }
+    live.count -= 1;
+    live.bytes -= layout.size();
+    let next = live.freed_next;
+    live.freed[next] = base as usize;
+    live.freed_next = (next + 1) % FREED_SLOTS;
+    drop(live);
+
+    (*record).magic = MAGIC_FREED;
+    data.write_bytes(FREE_POISON, layout.size());
+
+    base
+}
+
+unsafe fn check_redzone(zone: *mut u8, data: *mut u8, which: &str) {
+    for i in 0..REDZONE_SIZE {
+        let byte = zone.add(i).read_volatile();
+        if byte != REDZONE_BYTE {
+            let record = record_of(data);
+            panic!(
+                "Heap {} redzone of {:?} (size {}, seq {}) clobbered at byte {}: 0x{:x}. Allocated from {:x?}",
+                which,
+                data,
+                (*record).size,
+                (*record).seq,
+                i,
+                byte,
+                (*record).callers
+            );
+        }
+    }
+}
+
+/// Print every outstanding allocation, newest first.
+pub fn heap_report() {
+    let live = LIVE.lock();
+    log!(
+        Info,
+        "Heap report: {} live allocations, {} bytes",
+        live.count,
+        live.bytes
+    );
+    let mut curr = live.head;
+    while !curr.is_null() {
+        unsafe {
+            let data = curr.byte_add(size_of::<AllocRecord>() + REDZONE_SIZE);
+            println!(
+                "    #{} {:?} size {} from {:x?}",
+                (*curr).seq,
+                data,
+                (*curr).size,
+                (*curr).callers
+            );
+            curr = (*curr).next;
+        }
+    }
+}
@@ -0,0 +1,176 @@
+//! Physical memory discovery.
+//! Work out which physical ranges the page pool may hand out. We take the
+//! `/memory` nodes of the boot device tree, then carve out the kernel image,
+//! the device tree blob itself, the blob's memory reservation block and any
+//! `/reserved-memory` children. Without a device tree we fall back to
+//! `_memory_end` from `kernel.ld`.
+use crate::hw::fdt::{self, Fdt};
+use crate::hw::param::*;
+
+/// Most discontiguous ranges we keep track of.
+pub const MAX_REGIONS: usize = 16;
+
+/// Half open physical address range [start, end).
+#[derive(Copy, Clone, Debug)]
+pub struct MemRange {
+    pub start: usize,
+    pub end: usize,
+}
+
+/// Fixed capacity list of non-overlapping ranges.
+/// Fixed size since this is needed before there is a heap.
+#[derive(Copy, Clone)]
+pub struct MemMap {
+    ranges: [MemRange; MAX_REGIONS],
+    len: usize,
+}
+
+impl MemRange {
+    pub fn len(&self) -> usize {
+        self.end - self.start
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.end <= self.start
+    }
+
+    /// Shrink to whole pages.
+    fn page_align(&self) -> Self {
+        MemRange {
+            start: (self.start + PAGE_SIZE - 1) & !(PAGE_SIZE - 1),
+            end: self.end & !(PAGE_SIZE - 1),
+        }
+    }
+}
+
+impl MemMap {
+    pub const fn new() -> Self {
+        MemMap {
+            ranges: [MemRange { start: 0, end: 0 }; MAX_REGIONS],
+            len: 0,
+        }
+    }
+
+    pub fn ranges(&self) -> &[MemRange] {
+        &self.ranges[..self.len]
+    }
+
+    /// Add a range. Ranges past `MAX_REGIONS` are dropped with a warning.
+    pub fn add(&mut self, range: MemRange) {
+        if range.is_empty() {
+            return;
+        }
+        if self.len == MAX_REGIONS {
+            log!(
+                Warning,
+                "Too many memory ranges, ignoring {:#x}..{:#x}",
+                range.start,
+                range.end
+            );
+            return;
+        }
+        self.ranges[self.len] = range;
+        self.len += 1;
+    }
+
+    /// Remove `hole` from every range, splitting ranges where needed.
+    pub fn remove(&mut self, hole: MemRange) {
+        let old = *self;
+        self.len = 0;
+        for range in old.ranges() {
+            if hole.end <= range.start || hole.start >= range.end {
+                self.add(*range);
+                continue;
+            }
+            self.add(MemRange {
+                start: range.start,
+                end: hole.start,
+            });
+            self.add(MemRange {
+                start: hole.end,
+                end: range.end,
+            });
+        }
+    }
+}
+
+/// All RAM described by the device tree, or `DRAM_BASE..dram_end()`.
+pub fn dram() -> MemMap {
+    let mut map = MemMap::new();
+    match fdt::boot_fdt() {
+        Some(fdt) => {
+            for node in fdt.root().children() {
+                let is_memory = node
+                    .property("device_type")
+                    .and_then(|p| p.as_str())
+                    .map_or(node.base_name() == "memory", |t| t == "memory");
+                if !is_memory {
+                    continue;
+                }
+                for (addr, size) in node.reg() {
+                    map.add(MemRange {
+                        start: addr as usize,
+                        end: (addr + size) as usize,
+                    });
+                }
+            }
+        }
+        None => {
+            log!(Warning, "No device tree, assuming RAM ends at _memory_end");
+            map.add(MemRange {
+                start: DRAM_BASE.addr(),
+                end: dram_end().addr(),
+            });
+        }
+    }
+    map
+}
+
+/// Ranges the device tree asks us to stay out of.
+fn reserved(fdt: &Fdt) -> MemMap {
+    let mut map = MemMap::new();
+    // The blob itself.
+    map.add(MemRange {
+        start: fdt.addr(),
+        end: fdt.addr() + fdt.total_size(),
+    });
+    for (addr, size) in fdt.mem_reservations() {
+        map.add(MemRange {
+            start: addr as usize,
+            end: (addr + size) as usize,
+        });
+    }
+    if let Some(node) = fdt.find_node("/reserved-memory") {
+        for child in node.children() {
+            for (addr, size) in child.reg() {
+                map.add(MemRange {
+                    start: addr as usize,
+                    end: (addr + size) as usize,
+                });
+            }
+        }
+    }
+    map
+}
+
+/// Physical ranges free for the page pool: RAM minus the kernel image
+/// and anything reserved, shrunk to whole pages.
+pub fn usable() -> MemMap {
+    let mut map = dram();
+    // Kernel text, data, stacks and bss.
+    map.remove(MemRange {
+        start: DRAM_BASE.addr(),
+        end: bss_end().addr(),
+    });
+    if let Some(fdt) = fdt::boot_fdt() {
+        for hole in reserved(&fdt).ranges() {
+            map.remove(*hole);
+        }
+    }
+
+    let mut aligned = MemMap::new();
+    for range in map.ranges() {
+        aligned.add(range.page_align());
+    }
+    aligned
+}
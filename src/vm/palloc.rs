@@ -1,6 +1,6 @@
 //! Physical page allocator
 use crate::hw::param::*;
-use crate::lock::mutex::Mutex;
+use crate::lock::irq_mutex::IrqMutex;
 use crate::vm::VmError;
 
 /// Utility function, primarily used to check if addresses are page aligned.
@@ -10,7 +10,7 @@ fn is_multiple(addr: usize, size: usize) -> bool {
 
 /// Kernel page pool.
 pub struct PagePool {
-    pool: Mutex<Pool>, //[Mutex<Pool>; NHART + 1],
+    pool: IrqMutex<Pool>, //[Mutex<Pool>; NHART + 1],
 }
 
 /// Characterizes a page pool by tracking free pages with a double linked list.
@@ -18,6 +18,24 @@ struct Pool {
     free: Option<Page>, // Head of free page list (stored in the free pages).
     bottom: *mut usize, // Min addr of this page allocation pool.
     top: *mut usize,    // Max addr of this page allocation pool.
+    total: usize,       // Number of pages managed by this pool.
+    nfree: usize,       // Number of pages currently on the free list.
+    high_water: usize,  // Most pages ever in use at once.
+}
+
+// The free list lives in the pages themselves, and is only touched under
+// the pool lock.
+unsafe impl Send for Pool {}
+
+/// Snapshot of page pool usage. All counts are in pages.
+#[derive(Copy, Clone, Debug, Default)]
+pub struct PageStats {
+    pub total: usize,
+    pub free: usize,
+    pub used: usize,
+    pub high_water: usize,
+    /// Longest run of physically contiguous free pages.
+    pub largest_free_extent: usize,
 }
 
 /// Convenience struct to read a free page like a doubly linked list.
@@ -43,7 +61,7 @@ impl FreeNode {
 impl PagePool {
     /// Allocate page of physical memory by returning a pointer
     /// to the allocated page from the doubly linked free list.
-    pub fn palloc(&mut self) -> Result<Page, VmError> {
+    pub fn palloc(&self) -> Result<Page, VmError> {
         let mut pool = self.pool.lock();
         match pool.free {
             None => Err(VmError::OutOfPages),
@@ -56,7 +74,7 @@ impl PagePool {
 
     /// Free a page of physical memory by inserting into the doubly
     /// linked free list in order.
-    pub fn pfree(&mut self, page: Page) -> Result<(), VmError> {
+    pub fn pfree(&self, page: Page) -> Result<(), VmError> {
         if !is_multiple(page.addr.addr(), PAGE_SIZE) {
             panic!("Free page addr not page aligned.")
         }
@@ -66,7 +84,7 @@ impl PagePool {
         Ok(())
     }
 
-    pub fn palloc_plural(&mut self, num_pages: usize) -> Result<*mut usize, VmError> {
+    pub fn palloc_plural(&self, num_pages: usize) -> Result<*mut usize, VmError> {
         assert!(num_pages != 0, "tried to allocate zero pages");
         let mut pool = self.pool.lock();
         match pool.free {
@@ -79,7 +97,7 @@ impl PagePool {
         }
     }
 
-    pub fn pfree_plural(&mut self, page: *mut usize, num_pages: usize) -> Result<(), VmError> {
+    pub fn pfree_plural(&self, page: *mut usize, num_pages: usize) -> Result<(), VmError> {
         assert!(num_pages != 0, "tried to allocate zero pages");
         if !is_multiple(page.addr(), PAGE_SIZE) {
             panic!("Free page addr not page aligned.")
@@ -169,7 +187,12 @@ impl Pool {
         let mut free = Page::new(bottom);
         let mut pa = bottom.map_addr(|addr| addr + chunk_size);
         //let tmp = FreeNode::new(0x0 as *mut usize, pa); // First free page 'prev' == 0x0 => none.
-        free.write_free(core::ptr::null_mut::<usize>(), pa);
+        let next = if pa < top {
+            pa
+        } else {
+            core::ptr::null_mut::<usize>()
+        };
+        free.write_free(core::ptr::null_mut::<usize>(), next);
         let last = top.map_addr(|addr| addr - chunk_size);
         // Init the remainder of the free list.
         while pa < top {
@@ -186,11 +209,51 @@ impl Pool {
             pa = pa.map_addr(|addr| addr + chunk_size); // Don't use next_pa. End of loop will fail.
         }
 
+        let total = (top.addr() - bottom.addr()) / chunk_size;
         Pool {
             free: Some(free),
             bottom,
             top,
+            total,
+            nfree: total,
+            high_water: 0,
+        }
+    }
+
+    /// Hand another (non-overlapping) range of pages to this pool.
+    fn add_range(&mut self, bottom: *mut usize, top: *mut usize) {
+        let num_pages = (top.addr() - bottom.addr()) / PAGE_SIZE;
+        if num_pages == 0 {
+            return;
+        }
+        self.total += num_pages;
+        self.free_pages(Page::from(bottom), num_pages);
+        self.bottom = self.bottom.min(bottom);
+        self.top = self.top.max(top);
+    }
+
+    /// Length in pages of the longest run of contiguous free pages.
+    fn largest_free_extent(&self) -> usize {
+        let mut largest = 0;
+        let mut run = 0;
+        let mut last = core::ptr::null_mut::<usize>();
+        let mut curr = self.free;
+        while let Some(mut page) = curr {
+            if !last.is_null() && page.addr.addr() == last.addr() + PAGE_SIZE {
+                run += 1;
+            } else {
+                run = 1;
+            }
+            largest = largest.max(run);
+            last = page.addr;
+            let (_, next) = page.read_free();
+            curr = if next.is_null() {
+                None
+            } else {
+                Some(Page::from(next))
+            };
         }
+        largest
     }
 
     // If this is the last free page in the pool, set the free pool to None
@@ -257,6 +320,8 @@ impl Pool {
             cur = Page::from(cur.addr.map_addr(|addr| addr + 0x1000));
         }
 
+        self.nfree -= num_pages;
+        self.high_water = self.high_water.max(self.total - self.nfree);
         Ok(start_region)
     }
 
@@ -319,11 +384,13 @@ impl Pool {
                 self.free = Some(page);
             }
         }
+        self.nfree += num_pages;
     }
 }
 
 impl PagePool {
-    /// Create a new pool within a mutex spinlock.
+    /// Create a new pool within an interrupt safe spinlock, so trap
+    /// handlers can allocate.
     pub fn new(bottom: *mut usize, top: *mut usize) -> Self {
         assert!(is_multiple(bottom.addr(), PAGE_SIZE));
         assert!(is_multiple(top.addr(), PAGE_SIZE));
@@ -342,8 +409,29 @@ impl PagePool {
         //        Mutex::new(Pool::new(per_start, top))
         //    }
         //});
-        let pool = Mutex::new(Pool::new(bottom, top, PAGE_SIZE));
+        let pool = IrqMutex::new(Pool::new(bottom, top, PAGE_SIZE));
         PagePool { pool }
     }
+
+    /// Add a discontiguous range of physical memory to the pool. Used
+    /// when the device tree describes more than one usable RAM range.
+    pub fn add_range(&self, bottom: *mut usize, top: *mut usize) {
+        assert!(is_multiple(bottom.addr(), PAGE_SIZE));
+        assert!(is_multiple(top.addr(), PAGE_SIZE));
+        self.pool.lock().add_range(bottom, top);
+    }
+
+    /// Report current usage. Walks the free list to find the
+    /// largest contiguous free extent, so don't call this in a hot path.
+    pub fn stats(&self) -> PageStats {
+        let pool = self.pool.lock();
+        PageStats {
+            total: pool.total,
+            free: pool.nfree,
+            used: pool.total - pool.nfree,
+            high_water: pool.high_water,
+            largest_free_extent: pool.largest_free_extent(),
+        }
+    }
 }
 
@@ -5,20 +5,37 @@
 
 use crate::hw::HartContext;
 use crate::trap::TrapFrame;
+use crate::vm::asid::Asid;
 use crate::vm::ptable::PageTable;
-use crate::vm::Resource;
+use crate::vm::{self, PhysPageExtent, Resource};
 use alloc::boxed::Box;
 use alloc::collections::BTreeSet;
+use core::ops::Range;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+/// Next process id to hand out.
+static NEXT_PID: AtomicUsize = AtomicUsize::new(1);
 
 pub struct Process {
     id: usize,
+    name: &'static str,
     address_space: BTreeSet<Box<dyn Resource>>, // todo: Balanced BST of Resources
     state: ProcessState,
     pgtbl: PageTable,
+    asid: Asid,
     trapframe: TrapFrame,
     ctx_regs: HartContext,
+    /// Kernel stack, for kernel threads. Freed with the process.
+    kstack: Option<PhysPageExtent>,
+    /// What a kernel thread runs, taken when it first starts.
+    entry: Option<Box<dyn FnOnce() + Send>>,
 }
 
+// Processes move between harts through the run queue and wait queues, and
+// only ever run on one hart at a time.
+unsafe impl Send for Process {}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
 pub enum ProcessState {
     Ready,
     Run,
@@ -26,3 +43,82 @@ pub enum ProcessState {
     Sleep,
     Dead,
 }
+
+impl Process {
+    /// A kernel thread: runs `entry` in S-mode in the kernel's address space,
+    /// on `kstack`. The first switch to it returns into `start`, which is
+    /// expected to run `take_entry()`. See `sched::kthread`.
+    pub fn new_kernel(
+        name: &'static str,
+        kstack: PhysPageExtent,
+        entry: Box<dyn FnOnce() + Send>,
+        start: usize,
+    ) -> Self {
+        Process {
+            id: NEXT_PID.fetch_add(1, Ordering::Relaxed),
+            name,
+            address_space: BTreeSet::new(),
+            state: ProcessState::Ready,
+            pgtbl: vm::kernel_page_table(),
+            asid: Asid::new(),
+            trapframe: TrapFrame::new(),
+            ctx_regs: HartContext::entry(start, kstack.end().addr()),
+            kstack: Some(kstack),
+            entry: Some(entry),
+        }
+    }
+
+    pub fn id(&self) -> usize {
+        self.id
+    }
+
+    pub fn name(&self) -> &'static str {
+        self.name
+    }
+
+    /// Address range of this process' kernel stack, if it has its own.
+    pub fn kstack(&self) -> Option<Range<usize>> {
+        self.kstack
+            .as_ref()
+            .map(|stack| stack.start().addr()..stack.end().addr())
+    }
+
+    pub fn take_entry(&mut self) -> Option<Box<dyn FnOnce() + Send>> {
+        self.entry.take()
+    }
+
+    pub fn state(&self) -> &ProcessState {
+        &self.state
+    }
+
+    pub fn set_state(&mut self, state: ProcessState) {
+        self.state = state;
+    }
+
+    /// Where `hw::swtch` keeps this process' registers while it is off the hart.
+    pub fn context(&mut self) -> &mut HartContext {
+        &mut self.ctx_regs
+    }
+
+    /// Whether this process has an address space of its own, as opposed to
+    /// running on the kernel page table like kernel threads do.
+    fn own_page_table(&self) -> bool {
+        self.pgtbl.root() != vm::kernel_page_table().root()
+    }
+
+    /// Load this process' page table before switching to it. Tagged with
+    /// its ASID, so the TLB isn't flushed. Nothing to do for kernel
+    /// threads, the kernel page table is already loaded.
+    pub fn activate(&self) {
+        if self.own_page_table() {
+            self.pgtbl.write_satp_asid(&self.asid);
+        }
+    }
+
+    /// Go back to the kernel page table once this process is off the hart.
+    pub fn deactivate(&self) {
+        if self.own_page_table() {
+            vm::kernel_page_table().write_satp_kernel();
+        }
+    }
+}
@@ -1,8 +1,13 @@
 //! Page table
 // VA: 39bits, PA: 56bits
 // PTE size = 8 bytes
+use crate::device;
+use crate::hw::nharts;
 use crate::hw::param::*;
 use crate::hw::riscv::*;
+use crate::vm::asid::{self, Asid};
+use crate::vm::memmap::MemMap;
+use crate::vm::tlb;
 use crate::vm::*;
 use core::assert;
 
@@ -34,6 +39,11 @@ pub struct PageTable {
     base: PhysAddress, // Page Table located at base address.
 }
 
+// Just the address of the table. Harts share the kernel's, and changes to
+// it go through `tlb::shootdown`.
+unsafe impl Send for PageTable {}
+unsafe impl Sync for PageTable {}
+
 #[inline(always)]
 fn vpn(ptr: VirtAddress, level: usize) -> usize {
     ptr.addr() >> (12 + 9 * level) & 0x1FF
@@ -62,8 +72,8 @@ macro_rules! PteSetFlag {
 }
 
 #[inline(always)]
-fn phy_to_satp(ptr: PhysAddress) -> usize {
-    (1 << 63) | (ptr.addr() >> 12)
+fn phy_to_satp(ptr: PhysAddress, asid: usize) -> usize {
+    (1 << 63) | (asid << SATP_ASID_SHIFT) | (ptr.addr() >> 12)
 }
 
 macro_rules! PageAlignDown {
@@ -100,10 +110,73 @@ impl PageTable {
         assert!(idx < PTE_TOP);
         unsafe { get_phy_offset(self.base, idx) }
     }
+    /// Physical address of the root table.
+    pub fn root(&self) -> PhysAddress {
+        self.base
+    }
+
+    /// Load this table with ASID 0, flushing the whole TLB.
+    /// Meant for the kernel page table, see `write_satp_asid` for the rest.
     pub fn write_satp(&self) {
         flush_tlb();
-        write_satp(phy_to_satp(self.base));
+        write_satp(phy_to_satp(self.base, 0));
         flush_tlb();
+        tlb::activate(self);
+    }
+
+    /// Load the kernel page table again, after running an address space of
+    /// its own. ASID 0 entries all belong to this table, and changes to it
+    /// are shot down as they are made, so there is nothing to flush, unless
+    /// every address space is ASID 0 for lack of ASID support.
+    pub fn write_satp_kernel(&self) {
+        write_satp(phy_to_satp(self.base, 0));
+        if asid::bits() == 0 {
+            flush_tlb();
+        }
+        tlb::activate(self);
+    }
+
+    /// Load this table tagged with `asid`. Only flushes when the ASID
+    /// allocator says so, entries of other address spaces stay put.
+    pub fn write_satp_asid(&self, asid: &Asid) {
+        let id = asid::assign(asid);
+        write_satp(phy_to_satp(self.base, id));
+        tlb::activate(self);
+    }
+
+    /// Unmap the pages covering [va, va + size), then flush them from every
+    /// hart using this table. Does not free the physical pages. Stops at the
+    /// first page that isn't mapped, the pages before it stay unmapped (and
+    /// flushed).
+    pub fn unmap(&self, va: VirtAddress, size: usize) -> Result<(), VmError> {
+        if size == 0 {
+            return Ok(());
+        }
+        let first = PageAlignDown!(va);
+        let end = PageAlignDown!(va.map_addr(|addr| addr + (size - 1)));
+        let mut start = first;
+        let mut ret = Ok(());
+        while start <= end {
+            let pte_addr = match unsafe { walk(*self, start, false) } {
+                Ok(pte_addr) => pte_addr,
+                Err(e) => {
+                    ret = Err(e);
+                    break;
+                }
+            };
+            if read_pte(pte_addr) & PTE_VALID == 0 {
+                ret = Err(VmError::PfreeFail);
+                break;
+            }
+            set_pte(pte_addr, 0);
+            start = start.map_addr(|addr| addr + PAGE_SIZE);
+        }
+        // Flush what we did clear, even when we stopped early.
+        let cleared = start.addr() - first.addr();
+        if cleared != 0 {
+            tlb::shootdown(self, first.addr(), cleared);
+        }
+        ret
     }
 }
 
@@ -120,7 +193,7 @@ unsafe fn walk(pt: PageTable, va: VirtAddress, alloc_new: bool) -> Result<*mut P
             true => PageTable::from(*next),
             false => {
                 if alloc_new {
-                    match PAGEPOOL.get_mut().unwrap().palloc() {
+                    match pagepool().palloc() {
                         Ok(pg) => {
                             *next = PteSetFlag!(phy_to_pte(pg.addr), PTE_VALID);
                             PageTable::from(phy_to_pte(pg.addr))
@@ -175,32 +248,35 @@ fn page_map(
 
 /// Create the kernel page table with 1:1 mappings to physical memory.
 /// First allocate a new page for the kernel page table.
-/// Next, map memory mapped I/O devices to the kernel page table.
+/// Next, map memory mapped I/O devices found by `device::probe` to the kernel page table.
 /// Then map the kernel .text, .data, .rodata and .bss sections.
-/// Additionally, map a stack+guard page for each hart.
+/// Additionally, map the boot and interrupt stacks for each hart we run on.
 /// Finally map, the remaining physical memory to kernel virtual memory as
-/// the kernel 'heap'.
-pub fn kpage_init() -> Result<PageTable, VmError> {
-    let base = unsafe {
-        PAGEPOOL
-            .get_mut()
-            .unwrap()
-            .palloc()
-            .expect("Couldn't allocate root kernel page table.")
-    };
+/// the kernel 'heap'. `dram` is all of RAM, which may be several ranges.
+pub fn kpage_init(dram: &MemMap) -> Result<PageTable, VmError> {
+    let base = pagepool()
+        .palloc()
+        .expect("Couldn't allocate root kernel page table.");
     //log!(Debug, "Kernel page table base addr: {:#02x}", base.addr.addr());
     let kpage_table = PageTable {
         base: base.addr as *mut usize,
     };
 
-    page_map(
-        kpage_table,
-        UART_BASE as *mut usize,
-        UART_BASE as *mut usize,
-        PAGE_SIZE,
-        PTE_READ | PTE_WRITE,
-    )?;
-    log!(Debug, "Successfully mapped UART into kernel pgtable...");
+    for device in device::devices().iter() {
+        let base = device.base as *mut usize;
+        page_map(
+            kpage_table,
+            base,
+            base,
+            device.size.max(PAGE_SIZE),
+            PTE_READ | PTE_WRITE,
+        )?;
+        log!(
+            Debug,
+            "Successfully mapped {} into kernel pgtable...",
+            device.name
+        );
+    }
 
     page_map(
         kpage_table,
@@ -238,11 +314,8 @@ pub fn kpage_init() -> Result<PageTable, VmError> {
         "Succesfully mapped kernel data into kernel pgtable..."
     );
 
-    // This maps hart 0, 1 stack pages in opposite order as entry.S. Shouln't necessarily be a
-    // problem.
-    let base = stacks_start();
-    for s in 0..NHART {
-        let stack = unsafe { base.byte_add(PAGE_SIZE * (1 + s * 3)) };
+    for s in 0..nharts() {
+        let (stack, _) = hart_stack(s);
         page_map(
             kpage_table,
             stack,
@@ -257,12 +330,10 @@ pub fn kpage_init() -> Result<PageTable, VmError> {
         );
     }
 
-    // This maps hart 0, 1 stack pages in opposite order as entry.S. Shouln't necessarily be a
-    // problem.
-    let base = intstacks_start();
-    for i in 0..NHART {
-        let m_intstack = unsafe { base.byte_add(PAGE_SIZE * (1 + i * 4)) };
+    for i in 0..nharts() {
+        let (m_top, s_top) = hart_intstacks(i);
         // Map hart i m-mode handler.
+        let m_intstack = unsafe { m_top.byte_sub(PAGE_SIZE) };
         page_map(
             kpage_table,
             m_intstack,
@@ -271,7 +342,7 @@ pub fn kpage_init() -> Result<PageTable, VmError> {
             PTE_READ | PTE_WRITE,
         )?;
         // Map hart i s-mode handler
-        let s_intstack = unsafe { m_intstack.byte_add(PAGE_SIZE * 2) };
+        let s_intstack = unsafe { s_top.byte_sub(PAGE_SIZE) };
         page_map(
             kpage_table,
             s_intstack,
@@ -295,13 +366,22 @@ pub fn kpage_init() -> Result<PageTable, VmError> {
     )?;
     log!(Debug, "Succesfully mapped kernel bss...");
 
-    page_map(
-        kpage_table,
-        bss_end(),
-        bss_end(),
-        dram_end().addr() - bss_end().addr(),
-        PTE_READ | PTE_WRITE,
-    )?;
+    // Everything past the kernel image, reserved ranges and the device tree
+    // blob included. Reserved memory is still ours to read.
+    for range in dram.ranges() {
+        let start = range.start.max(bss_end().addr()) & !(PAGE_SIZE - 1);
+        let end = (range.end + PAGE_SIZE - 1) & !(PAGE_SIZE - 1);
+        if start >= end {
+            continue;
+        }
+        page_map(
+            kpage_table,
+            start as *mut usize,
+            start as *mut usize,
+            end - start,
+            PTE_READ | PTE_WRITE,
+        )?;
+    }
     log!(Debug, "Succesfully mapped kernel heap...");
 
     Ok(kpage_table)
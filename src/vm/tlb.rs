@@ -0,0 +1,126 @@
+//! TLB shootdown.
+//! `sfence.vma` only affects the hart that runs it, so after changing a page
+//! table every other hart that has it loaded in `satp` must flush too. We
+//! remember which root each hart has loaded, and when a table changes we
+//! flush locally and make the other harts using it flush through an IPI
+//! (or the SBI RFENCE extension under OpenSBI), waiting until they have.
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use crate::hw::param::{MAX_HARTS, PAGE_SIZE};
+use crate::hw::riscv::*;
+use crate::hw::{self, HartSet};
+#[cfg(not(feature = "sbi"))]
+use crate::ipi;
+use crate::vm::asid::{self, Asid};
+use crate::vm::ptable::PageTable;
+
+/// Past this many pages a full flush is cheaper than one `sfence.vma` per page.
+const FLUSH_ALL_PAGES: usize = 64;
+
+#[allow(clippy::declare_interior_mutable_const)]
+const NO_ROOT: AtomicUsize = AtomicUsize::new(0);
+/// Physical address of the root page table each hart has in `satp`.
+static ACTIVE: [AtomicUsize; MAX_HARTS] = [NO_ROOT; MAX_HARTS];
+
+/// A flush request handed to other harts by address.
+#[cfg(not(feature = "sbi"))]
+struct Flush {
+    start: usize,
+    size: usize,
+    asid: Option<usize>,
+}
+
+/// Note that the calling hart just loaded `pt` into `satp`.
+pub fn activate(pt: &PageTable) {
+    ACTIVE[hw::hartid()].store(pt.root().addr(), Ordering::Release);
+}
+
+/// Harts that currently have `pt` loaded.
+pub fn harts_using(pt: &PageTable) -> HartSet {
+    let root = pt.root().addr();
+    let mut harts = HartSet::empty();
+    for hart in 0..hw::nharts() {
+        if ACTIVE[hart].load(Ordering::Acquire) == root {
+            harts.insert(hart);
+        }
+    }
+    harts
+}
+
+/// Flush [start, start + size) from this hart's TLB, either for every
+/// ASID or only for `asid`.
+fn flush_local(start: usize, size: usize, asid: Option<usize>) {
+    if size > FLUSH_ALL_PAGES * PAGE_SIZE {
+        match asid {
+            Some(asid) => flush_tlb_asid(asid),
+            None => flush_tlb(),
+        }
+        return;
+    }
+    let mut page = start & !(PAGE_SIZE - 1);
+    while page < start + size {
+        match asid {
+            Some(asid) => flush_tlb_page_asid(page, asid),
+            None => flush_tlb_page(page),
+        }
+        page += PAGE_SIZE;
+    }
+}
+
+/// Runs on the remote harts, `arg` points at the sender's `Flush`.
+#[cfg(not(feature = "sbi"))]
+fn flush_remote(arg: usize) {
+    let flush = unsafe { &*(arg as *const Flush) };
+    flush_local(flush.start, flush.size, flush.asid);
+}
+
+/// Flush [start, start + size) of `pt` on every hart using it, and return
+/// once all of them have. Call after changing `pt`, with interrupts on.
+pub fn shootdown(pt: &PageTable, start: usize, size: usize) {
+    shootdown_inner(pt, start, size, None);
+}
+
+/// Like `shootdown`, but only drop entries tagged with `pt`'s ASID. Entries
+/// of the same range in other address spaces survive. Without an ASID from
+/// the current generation (after a rollover, or with no ASID support) we
+/// can't tell which tag other harts use for `pt`, so flush every tag.
+pub fn shootdown_asid(pt: &PageTable, asid: &Asid, start: usize, size: usize) {
+    shootdown_inner(pt, start, size, asid::current(asid));
+}
+
+fn shootdown_inner(pt: &PageTable, start: usize, size: usize, asid: Option<usize>) {
+    let mut harts = harts_using(pt);
+    if harts.contains(hw::hartid()) {
+        flush_local(start, size, asid);
+        harts.remove(hw::hartid());
+    }
+    if harts.is_empty() {
+        return;
+    }
+
+    // The firmware only returns once the remote harts have fenced.
+    #[cfg(feature = "sbi")]
+    {
+        let mask = hw::sbi::HartMask {
+            mask: harts.bits(),
+            base: 0,
+        };
+        let result = match asid {
+            Some(asid) => hw::sbi::remote_sfence_vma_asid(mask, start, size, asid),
+            None => hw::sbi::remote_sfence_vma(mask, start, size),
+        };
+        if let Err(e) = result {
+            panic!("SBI remote sfence.vma failed: {:?}", e);
+        }
+    }
+    #[cfg(not(feature = "sbi"))]
+    {
+        let flush = Flush { start, size, asid };
+        ipi::call_on(harts, flush_remote, &flush as *const Flush as usize);
+    }
+}
+
+/// Flush everything of `pt` on every hart using it.
+pub fn shootdown_all(pt: &PageTable) {
+    shootdown(pt, 0, usize::MAX);
+}
@@ -111,6 +111,60 @@ struct Zone {
 pub struct Kalloc {
     head: *mut usize, // Address of first zone.
     end: *mut usize,
+    stats: KallocStats,
+}
+
+// Zones are pages of our own, only reached through the `Galloc` lock.
+unsafe impl Send for Kalloc {}
+
+/// Upper bound (inclusive) in bytes of each size class reported by
+/// `KallocStats`. The last class catches everything up to `MAX_CHUNK_SIZE`.
+pub const SIZE_CLASSES: [usize; 10] = [8, 16, 32, 64, 128, 256, 512, 1024, 2048, MAX_CHUNK_SIZE];
+
+/// Usage counters for one `SIZE_CLASSES` bucket.
+#[derive(Copy, Clone, Debug, Default)]
+pub struct SizeClassStats {
+    pub in_use: usize,     // Chunks currently allocated.
+    pub high_water: usize, // Most chunks ever allocated at once.
+    pub total_allocs: usize,
+}
+
+/// Snapshot of Kalloc usage.
+#[derive(Copy, Clone, Debug, Default)]
+pub struct KallocStats {
+    pub zones: usize, // Zones (pages) currently in the pool.
+    pub zones_high_water: usize,
+    pub bytes_in_use: usize, // Sum of chunk sizes handed out, excluding headers.
+    pub bytes_high_water: usize,
+    pub classes: [SizeClassStats; SIZE_CLASSES.len()],
+}
+
+fn size_class(size: usize) -> usize {
+    SIZE_CLASSES
+        .iter()
+        .position(|&max| size <= max)
+        .unwrap_or(SIZE_CLASSES.len() - 1)
+}
+
+impl KallocStats {
+    fn record_alloc(&mut self, size: usize) {
+        let class = &mut self.classes[size_class(size)];
+        class.in_use += 1;
+        class.total_allocs += 1;
+        class.high_water = class.high_water.max(class.in_use);
+        self.bytes_in_use += size;
+        self.bytes_high_water = self.bytes_high_water.max(self.bytes_in_use);
+    }
+
+    fn record_free(&mut self, size: usize) {
+        self.classes[size_class(size)].in_use -= 1;
+        self.bytes_in_use -= size;
+    }
+
+    fn record_zones(&mut self, delta: isize) {
+        self.zones = self.zones.checked_add_signed(delta).unwrap();
+        self.zones_high_water = self.zones_high_water.max(self.zones);
+    }
 }
 
 #[derive(Debug)]
@@ -353,12 +407,20 @@ impl Kalloc {
         unsafe {
             write_zone_header_pair(&zone, &head);
         }
+        let mut stats = KallocStats::default();
+        stats.record_zones(1);
         Kalloc {
             head: start.addr,
             end: start.addr.map_addr(|addr| addr + 0x1000),
+            stats,
         }
     }
 
+    /// Current usage counters.
+    pub fn stats(&self) -> KallocStats {
+        self.stats
+    }
+
     fn grow_pool(&self, tail: &mut Zone) -> Result<(Zone, Header), VmError> {
         let page = palloc()?;
         unsafe {
@@ -372,7 +434,7 @@ impl Kalloc {
         Ok((zone, head))
     }
 
-    fn shrink_pool(&self, mut drop_zone: Zone) {
+    fn shrink_pool(&mut self, mut drop_zone: Zone) {
         if drop_zone.base != self.head {
             let mut curr_ptr = self.head;
             //let mut curr_zone = Zone::from(curr_ptr);
@@ -383,6 +445,7 @@ impl Kalloc {
                 if let Some(next_zone) = curr_zone.next_zone() {
                     if drop_zone.base == next_zone.base {
                         drop_zone.free_self(curr_zone);
+                        self.stats.record_zones(-1);
                         return;
                     } else {
                         curr_ptr = next_zone.base;
@@ -414,18 +477,21 @@ impl Kalloc {
         let curr = self.head;
         let end = self.end.map_addr(|addr| addr - 0x1000);
         let mut zone = Zone::from(curr);
-        let mut trail = zone;
 
         while zone.base <= end {
             if let Some(ptr) = zone.scan(size) {
+                self.stats.record_alloc(size);
                 return Ok(ptr);
             } else {
                 zone = match zone.next_zone() {
                     Some(zone) => zone,
                     None => {
-                        if let Ok((mut zone, mut head)) = self.grow_pool(&mut trail) {
+                        // `zone` is the tail here, link the new zone after it.
+                        if let Ok((mut zone, mut head)) = self.grow_pool(&mut zone) {
                             let head_ptr = zone.base.map_addr(|addr| addr + ZONE_SIZE);
                             alloc_chunk(size, head_ptr, &mut zone, &mut head);
+                            self.stats.record_zones(1);
+                            self.stats.record_alloc(size);
                             return Ok(head_ptr.map_addr(|addr| addr + HEADER_SIZE));
                         } else {
                             return Err(KallocError::OOM);
@@ -449,6 +515,7 @@ impl Kalloc {
         let mut head = Header::from(head_ptr);
         assert!(!head.is_free(), "Kalloc double free.");
         head.set_unused();
+        self.stats.record_free(head.chunk_size());
 
         let mut chunk_merge_flag = false;
         if let Ok(count) = zone.decrement_refs() {